@@ -0,0 +1,649 @@
+//! `#[derive(RunpodParams)]` for the request params of `runpod-client` backends.
+//!
+//! The derive writes what every params struct used to spell out by hand:
+//!
+//! - `new()`, returning `Default::default()`.
+//! - The `RunpodParams` and `ParamFields` impls.
+//! - A `Serialize` impl that leaves out `None` fields. It honours serde's `rename`,
+//!   `flatten`, `skip` and `skip_serializing_if` attributes, so the struct keeps
+//!   deriving `Deserialize`. Serde attributes that would change serialization in any
+//!   other way, such as `serialize_with` or `rename_all`, are a compile error.
+//! - Range checks, from `#[runpod(range(...))]`.
+//! - With `#[runpod(builder = Trait)]`, the builder trait. Each setter is documented
+//!   with its field's doc comment and range.
+//!
+//! Struct attributes, inside `#[runpod(...)]`:
+//!
+//! - `builder = Trait` declares `Trait` with a `with_<field>` setter per field and a
+//!   `build()`, and implements it.
+//! - `declared` only implements `Trait`. Use it when the trait is written by hand
+//!   because it has default methods of its own.
+//! - `validate` makes `build()` return `Result<Self, anyhow::Error>` after calling the
+//!   struct's own `validate()`.
+//! - `no_new` leaves out `new()`, for structs whose constructor takes required fields.
+//!
+//! Field attributes, inside `#[runpod(...)]`:
+//!
+//! - `setter = name` renames the setter. Fields that share a setter name are set by a
+//!   single method that takes one argument per field.
+//! - `skip` leaves the field out of the builder.
+//! - `range(min = .., max = .., exclusive_min, exclusive_max)` bounds a numeric field.
+//!
+//! The generated code refers to the crate as `::rpc`, and needs `serde` and `anyhow` in
+//! scope of the crate it is used in.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ format_ident, quote, ToTokens };
+use syn::{
+    ext::IdentExt,
+    meta::ParseNestedMeta,
+    parenthesized,
+    parse_macro_input,
+    token,
+    Attribute,
+    Data,
+    DeriveInput,
+    Error,
+    Expr,
+    Fields,
+    GenericArgument,
+    Ident,
+    LitStr,
+    Path,
+    PathArguments,
+    Result,
+    Token,
+    Type,
+};
+
+#[proc_macro_derive(RunpodParams, attributes(runpod))]
+pub fn derive_runpod_params(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+#[derive(Default)]
+struct StructOptions {
+    builder: Option<Ident>,
+    declared: bool,
+    validate: bool,
+    no_new: bool,
+}
+
+#[derive(Default)]
+struct Range {
+    min: Option<Expr>,
+    max: Option<Expr>,
+    exclusive_min: bool,
+    exclusive_max: bool,
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    /// `T` when the field is an `Option<T>`.
+    option: Option<Type>,
+    docs: Vec<Attribute>,
+    name: String,
+    flatten: bool,
+    skip_serializing: bool,
+    skip_serializing_if: Option<Path>,
+    skip_builder: bool,
+    setter: Ident,
+    range: Option<Range>,
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let options = parse_struct_options(&input.attrs)?;
+    let fields = match &input.data {
+        Data::Struct(data) =>
+            match &data.fields {
+                Fields::Named(named) => named.named.iter().map(parse_field).collect::<Result<Vec<_>>>()?,
+                _ => {
+                    return Err(Error::new_spanned(&input.ident, "RunpodParams needs a struct with named fields"));
+                }
+            }
+        _ => {
+            return Err(Error::new_spanned(&input.ident, "RunpodParams can only be derived for structs"));
+        }
+    };
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let serialize_fields = fields
+        .iter()
+        .filter(|f| !f.skip_serializing)
+        .map(|f| {
+            let ident = &f.ident;
+            let key = &f.name;
+            let entry = if f.flatten {
+                quote! { ::rpc::backend::backend::ParamFields::serialize_fields(&self.#ident, map)?; }
+            } else if f.option.is_some() {
+                quote! {
+                    if let ::core::option::Option::Some(value) = &self.#ident {
+                        ::serde::ser::SerializeMap::serialize_entry(map, #key, value)?;
+                    }
+                }
+            } else {
+                quote! { ::serde::ser::SerializeMap::serialize_entry(map, #key, &self.#ident)?; }
+            };
+            match &f.skip_serializing_if {
+                Some(skip) =>
+                    quote! {
+                    if !#skip(&self.#ident) {
+                        #entry
+                    }
+                },
+                None => entry,
+            }
+        });
+
+    let range_checks = fields.iter().map(range_check);
+
+    let builder = match &options.builder {
+        Some(builder) => expand_builder(name, vis, builder, &options, &fields)?,
+        None => TokenStream2::new(),
+    };
+
+    let new = if options.no_new {
+        TokenStream2::new()
+    } else {
+        quote! {
+            impl #impl_generics #name #ty_generics #where_clause {
+                #vis fn new() -> Self {
+                    ::core::default::Default::default()
+                }
+            }
+        }
+    };
+
+    Ok(
+        quote! {
+        #new
+
+        impl #impl_generics ::rpc::backend::backend::RunpodParams for #name #ty_generics #where_clause {}
+
+        impl #impl_generics ::rpc::backend::backend::ParamFields for #name #ty_generics #where_clause {
+            fn serialize_fields<M: ::serde::ser::SerializeMap>(
+                &self,
+                map: &mut M
+            ) -> ::core::result::Result<(), M::Error> {
+                #(#serialize_fields)*
+                ::core::result::Result::Ok(())
+            }
+
+            fn check_ranges(&self) -> ::core::result::Result<(), ::anyhow::Error> {
+                #(#range_checks)*
+                ::core::result::Result::Ok(())
+            }
+        }
+
+        impl #impl_generics ::serde::Serialize for #name #ty_generics #where_clause {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error> {
+                let mut map = ::serde::Serializer::serialize_map(serializer, ::core::option::Option::None)?;
+                ::rpc::backend::backend::ParamFields::serialize_fields(self, &mut map)?;
+                ::serde::ser::SerializeMap::end(map)
+            }
+        }
+
+        #builder
+    }
+    )
+}
+
+fn expand_builder(
+    name: &Ident,
+    vis: &syn::Visibility,
+    builder: &Ident,
+    options: &StructOptions,
+    fields: &[Field]
+) -> Result<TokenStream2> {
+    // Fields sharing a setter are set together, in declaration order.
+    let mut groups: Vec<(&Ident, Vec<&Field>)> = Vec::new();
+    for field in fields.iter().filter(|f| !f.skip_builder) {
+        match groups.iter_mut().find(|(setter, _)| **setter == field.setter) {
+            Some((_, group)) => group.push(field),
+            None => groups.push((&field.setter, vec![field])),
+        }
+    }
+
+    let (build_output, build_body) = if options.validate {
+        (
+            quote! { ::core::result::Result<#name, ::anyhow::Error> },
+            quote! {
+                self.validate()?;
+                ::core::result::Result::Ok(self)
+            },
+        )
+    } else {
+        (quote! { #name }, quote! { self })
+    };
+
+    let mut declarations = Vec::new();
+    let mut implementations = Vec::new();
+    for (setter, group) in &groups {
+        let args = group.iter().map(|f| {
+            let ident = &f.ident;
+            let ty = f.option.as_ref().unwrap_or(&f.ty);
+            quote! { #ident: #ty }
+        });
+        let args = quote! { #(#args),* };
+        let assignments = group.iter().map(|f| {
+            let ident = &f.ident;
+            if f.option.is_some() {
+                quote! { self.#ident = ::core::option::Option::Some(#ident); }
+            } else {
+                quote! { self.#ident = #ident; }
+            }
+        });
+
+        let docs = setter_docs(group);
+        declarations.push(quote! {
+            #(#docs)*
+            fn #setter(self, #args) -> Self;
+        });
+        implementations.push(
+            quote! {
+            fn #setter(mut self, #args) -> Self {
+                #(#assignments)*
+                self
+            }
+        }
+        );
+    }
+
+    let declaration = if options.declared {
+        TokenStream2::new()
+    } else {
+        let doc = std::format!(" Builder methods for [`{}`].", name);
+        quote! {
+            #[doc = #doc]
+            #vis trait #builder {
+                #(#declarations)*
+                fn build(self) -> #build_output;
+            }
+        }
+    };
+
+    Ok(
+        quote! {
+        #declaration
+
+        impl #builder for #name {
+            #(#implementations)*
+
+            fn build(self) -> #build_output {
+                #build_body
+            }
+        }
+    }
+    )
+}
+
+fn setter_docs(group: &[&Field]) -> Vec<TokenStream2> {
+    let mut docs: Vec<TokenStream2> = group
+        .iter()
+        .flat_map(|f| f.docs.iter().map(|d| d.to_token_stream()))
+        .collect();
+    if docs.is_empty() {
+        let names: Vec<String> = group
+            .iter()
+            .map(|f| std::format!("`{}`", f.name))
+            .collect();
+        let doc = std::format!(" Sets {}.", names.join(" and "));
+        docs.push(quote! { #[doc = #doc] });
+    }
+    for field in group {
+        if let Some(range) = &field.range {
+            let doc = std::format!(" {}.", range_message(&field.name, range));
+            docs.push(quote! { #[doc = ""] });
+            docs.push(quote! { #[doc = #doc] });
+        }
+    }
+    docs
+}
+
+fn range_check(field: &Field) -> TokenStream2 {
+    let ident = &field.ident;
+    if field.flatten {
+        return quote! { ::rpc::backend::backend::ParamFields::check_ranges(&self.#ident)?; };
+    }
+    let range = match &field.range {
+        Some(range) => range,
+        None => {
+            return TokenStream2::new();
+        }
+    };
+
+    let mut conditions = Vec::new();
+    if let Some(min) = &range.min {
+        conditions.push(if range.exclusive_min { quote! { value > #min } } else { quote! { value >= #min } });
+    }
+    if let Some(max) = &range.max {
+        conditions.push(if range.exclusive_max { quote! { value < #max } } else { quote! { value <= #max } });
+    }
+    // One check per bound, so clippy doesn't ask for a range in generated code.
+    let message = range_message(&field.name, range);
+    let check =
+        quote! {
+        #(
+            if !(#conditions) {
+                return ::core::result::Result::Err(::anyhow::Error::msg(#message));
+            }
+        )*
+    };
+
+    if field.option.is_some() {
+        quote! {
+            if let ::core::option::Option::Some(value) = self.#ident {
+                #check
+            }
+        }
+    } else {
+        quote! {
+            let value = self.#ident;
+            #check
+        }
+    }
+}
+
+fn range_message(name: &str, range: &Range) -> String {
+    let show = |expr: &Expr| expr.to_token_stream().to_string().replace(' ', "");
+    match (&range.min, &range.max) {
+        (Some(min), Some(max)) =>
+            std::format!(
+                "{} must be in {}{}, {}{}",
+                name,
+                if range.exclusive_min { "(" } else { "[" },
+                show(min),
+                show(max),
+                if range.exclusive_max { ")" } else { "]" }
+            ),
+        (Some(min), None) if range.exclusive_min => std::format!("{} must be greater than {}", name, show(min)),
+        (Some(min), None) => std::format!("{} must be at least {}", name, show(min)),
+        (None, Some(max)) if range.exclusive_max => std::format!("{} must be less than {}", name, show(max)),
+        (None, Some(max)) => std::format!("{} must be at most {}", name, show(max)),
+        (None, None) => std::format!("{} is unbounded", name),
+    }
+}
+
+/// Serde struct attributes that only affect deserializing, or nothing the generated
+/// `Serialize` impl looks at.
+const STRUCT_SERDE_KEYS: [&str; 6] = ["default", "deny_unknown_fields", "expecting", "from", "rename", "try_from"];
+
+/// Serde field attributes that only affect deserializing. `rename`, `flatten`, `skip`,
+/// `skip_serializing` and `skip_serializing_if` are handled on top of these.
+const FIELD_SERDE_KEYS: [&str; 5] = ["alias", "borrow", "default", "deserialize_with", "skip_deserializing"];
+
+/// Consumes the `= value` or `(...)` after an attribute key that is accepted but unused.
+fn skip_meta_value(meta: &ParseNestedMeta) -> Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(token::Paren) {
+        let content;
+        parenthesized!(content in meta.input);
+        content.parse::<TokenStream2>()?;
+    }
+    Ok(())
+}
+
+fn unsupported_serde(meta: &ParseNestedMeta) -> Error {
+    let key = meta.path.to_token_stream().to_string();
+    meta.error(
+        std::format!("`#[serde({})]` isn't supported by RunpodParams, whose Serialize impl would ignore it", key)
+    )
+}
+
+fn parse_struct_options(attrs: &[Attribute]) -> Result<StructOptions> {
+    let mut options = StructOptions::default();
+    for attr in attrs {
+        if attr.path().is_ident("serde") {
+            attr.parse_nested_meta(|meta| {
+                if STRUCT_SERDE_KEYS.iter().any(|key| meta.path.is_ident(key)) {
+                    skip_meta_value(&meta)
+                } else {
+                    Err(unsupported_serde(&meta))
+                }
+            })?;
+        } else if attr.path().is_ident("runpod") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("builder") {
+                    options.builder = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("declared") {
+                    options.declared = true;
+                } else if meta.path.is_ident("validate") {
+                    options.validate = true;
+                } else if meta.path.is_ident("no_new") {
+                    options.no_new = true;
+                } else {
+                    return Err(meta.error("expected `builder`, `declared`, `validate` or `no_new`"));
+                }
+                Ok(())
+            })?;
+        }
+    }
+    if options.declared && options.builder.is_none() {
+        return Err(Error::new_spanned(&attrs[0], "`declared` needs `builder = Trait`"));
+    }
+    Ok(options)
+}
+
+fn parse_field(field: &syn::Field) -> Result<Field> {
+    let ident = field.ident.clone().expect("named field");
+    let mut parsed = Field {
+        setter: format_ident!("with_{}", ident.unraw()),
+        name: ident.unraw().to_string(),
+        option: option_inner(&field.ty),
+        ty: field.ty.clone(),
+        docs: field.attrs
+            .iter()
+            .filter(|a| a.path().is_ident("doc"))
+            .cloned()
+            .collect(),
+        flatten: false,
+        skip_serializing: false,
+        skip_serializing_if: None,
+        skip_builder: false,
+        range: None,
+        ident,
+    };
+
+    for attr in &field.attrs {
+        if attr.path().is_ident("serde") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    if !meta.input.peek(Token![=]) {
+                        return Err(meta.error("RunpodParams only supports `rename = \"name\"`"));
+                    }
+                    parsed.name = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("flatten") {
+                    parsed.flatten = true;
+                } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") {
+                    parsed.skip_serializing = true;
+                } else if meta.path.is_ident("skip_serializing_if") {
+                    parsed.skip_serializing_if = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                } else if FIELD_SERDE_KEYS.iter().any(|key| meta.path.is_ident(key)) {
+                    skip_meta_value(&meta)?;
+                } else {
+                    return Err(unsupported_serde(&meta));
+                }
+                Ok(())
+            })?;
+        } else if attr.path().is_ident("runpod") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("setter") {
+                    parsed.setter = meta.value()?.parse()?;
+                } else if meta.path.is_ident("skip") {
+                    parsed.skip_builder = true;
+                } else if meta.path.is_ident("range") {
+                    let mut range = Range::default();
+                    meta.parse_nested_meta(|bound| {
+                        if bound.path.is_ident("min") {
+                            range.min = Some(bound.value()?.parse()?);
+                        } else if bound.path.is_ident("max") {
+                            range.max = Some(bound.value()?.parse()?);
+                        } else if bound.path.is_ident("exclusive_min") {
+                            range.exclusive_min = true;
+                        } else if bound.path.is_ident("exclusive_max") {
+                            range.exclusive_max = true;
+                        } else {
+                            return Err(bound.error("expected `min`, `max`, `exclusive_min` or `exclusive_max`"));
+                        }
+                        Ok(())
+                    })?;
+                    if range.min.is_none() && range.max.is_none() {
+                        return Err(meta.error("`range` needs a `min` or a `max`"));
+                    }
+                    parsed.range = Some(range);
+                } else {
+                    return Err(meta.error("expected `setter`, `skip` or `range`"));
+                }
+                Ok(())
+            })?;
+        }
+    }
+    Ok(parsed)
+}
+
+fn option_inner(ty: &Type) -> Option<Type> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => {
+            return None;
+        }
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 =>
+            match args.args.first()? {
+                GenericArgument::Type(inner) => Some(inner.clone()),
+                _ => None,
+            }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::{ parse_quote, DeriveInput };
+
+    use super::expand;
+
+    /// The expansion with whitespace removed, so it can be searched for snippets.
+    fn expanded(input: DeriveInput) -> String {
+        expand(input).unwrap().to_string().replace(' ', "")
+    }
+
+    fn error(input: DeriveInput) -> String {
+        match expand(input) {
+            Ok(_) => panic!("expansion should fail"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_serialize_honours_serde_attributes() {
+        let code = expanded(
+            parse_quote! {
+            #[serde(deny_unknown_fields)]
+            struct Params {
+                #[serde(rename = "image_url", alias = "image")]
+                init_image: Option<String>,
+                #[serde(skip_serializing_if = "Vec::is_empty", default)]
+                styles: Vec<String>,
+                #[serde(skip)]
+                batch_size: Option<usize>,
+                #[serde(flatten)]
+                core: Core,
+            }
+        }
+        );
+
+        assert!(code.contains("serialize_entry(map,\"image_url\",value)"));
+        assert!(
+            code.contains("if!Vec::is_empty(&self.styles){::serde::ser::SerializeMap::serialize_entry(map,\"styles\"")
+        );
+        assert!(!code.contains("\"batch_size\""));
+        assert!(code.contains("ParamFields::serialize_fields(&self.core,map)"));
+        assert!(code.contains("fnnew()"));
+    }
+
+    #[test]
+    fn test_unsupported_serde_attributes_are_errors() {
+        let message = error(parse_quote! {
+            struct Params {
+                #[serde(serialize_with = "as_string")]
+                seed: Option<i64>,
+            }
+        });
+        assert!(message.contains("`#[serde(serialize_with)]` isn't supported"), "{}", message);
+
+        let message = error(parse_quote! {
+            #[serde(rename_all = "camelCase")]
+            struct Params {
+                guidance_scale: Option<f64>,
+            }
+        });
+        assert!(message.contains("`#[serde(rename_all)]` isn't supported"), "{}", message);
+
+        let message = error(parse_quote! {
+            struct Params {
+                #[serde(rename(serialize = "a", deserialize = "b"))]
+                prompt: Option<String>,
+            }
+        });
+        assert!(message.contains("only supports `rename = \"name\"`"), "{}", message);
+    }
+
+    #[test]
+    fn test_builder_and_options() {
+        let code = expanded(
+            parse_quote! {
+            #[runpod(builder = ParamBuilder, validate, no_new)]
+            pub struct Params {
+                #[runpod(setter = with_resolution)]
+                width: Option<u64>,
+                #[runpod(setter = with_resolution)]
+                height: Option<u64>,
+                #[runpod(range(min = 0.0, exclusive_min))]
+                strength: Option<f64>,
+                #[runpod(skip)]
+                core: Core,
+            }
+        }
+        );
+
+        assert!(!code.contains("fnnew()"));
+        assert!(code.contains("fnwith_resolution(self,width:u64,height:u64)->Self;"));
+        assert!(code.contains("fnwith_strength(self,strength:f64)->Self;"));
+        assert!(!code.contains("fnwith_core"));
+        assert!(code.contains("fnbuild(self)->::core::result::Result<Params,::anyhow::Error>;"));
+        assert!(code.contains("if!(value>0.0){"));
+        assert!(code.contains("\"strengthmustbegreaterthan0.0\""));
+    }
+
+    #[test]
+    fn test_invalid_runpod_attributes() {
+        assert!(
+            error(parse_quote! {
+                #[runpod(declared)]
+                struct Params {}
+            }).contains("`declared` needs `builder = Trait`")
+        );
+        assert!(
+            error(parse_quote! {
+                struct Params {
+                    #[runpod(range(exclusive_min))]
+                    strength: Option<f64>,
+                }
+            }).contains("`range` needs a `min` or a `max`")
+        );
+        assert!(error(parse_quote! { enum Params {} }).contains("can only be derived for structs"));
+    }
+}
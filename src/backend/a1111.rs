@@ -0,0 +1,692 @@
+use std::{ fmt, str::FromStr };
+
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ de::DeserializeOwned, ser::{ Error as _, SerializeMap }, Deserialize, Serialize, Serializer };
+use serde_json::{ json, Map, Value };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{
+    backend::{ RunpodBackend, RunpodParams },
+    diffusion::{ validate_resolution, DiffusionOutputFetch, DiffusionResult },
+    image::ImageInput,
+    job,
+};
+
+/// Largest `width * height` accepted before a request is sent.
+pub const A1111_MAX_PIXELS: u64 = 2048 * 2048;
+
+pub struct A1111;
+
+impl RunpodBackend for A1111 {}
+
+/// A call to the web UI API that the worker forwards: which route it goes to and what
+/// it returns.
+pub trait A1111Payload: RunpodParams + Serialize + Send + 'static {
+    const METHOD: &'static str;
+    const ENDPOINT: &'static str;
+    type Output: DeserializeOwned + Send + 'static;
+
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub type A1111Result<O> = DiffusionResult<O>;
+
+/// Sampler names as the web UI spells them. `Custom` passes any other name through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum A1111Sampler {
+    Euler,
+    EulerA,
+    Heun,
+    LMS,
+    DDIM,
+    UniPC,
+    DPMPP2M,
+    DPMPPSDE,
+    DPMPP2MSDE,
+    Custom(String),
+}
+
+impl A1111Sampler {
+    pub fn as_str(&self) -> &str {
+        match self {
+            A1111Sampler::Euler => "Euler",
+            A1111Sampler::EulerA => "Euler a",
+            A1111Sampler::Heun => "Heun",
+            A1111Sampler::LMS => "LMS",
+            A1111Sampler::DDIM => "DDIM",
+            A1111Sampler::UniPC => "UniPC",
+            A1111Sampler::DPMPP2M => "DPM++ 2M",
+            A1111Sampler::DPMPPSDE => "DPM++ SDE",
+            A1111Sampler::DPMPP2MSDE => "DPM++ 2M SDE",
+            A1111Sampler::Custom(name) => name.as_str(),
+        }
+    }
+}
+
+impl fmt::Display for A1111Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for A1111Sampler {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "Euler" => A1111Sampler::Euler,
+            "Euler a" => A1111Sampler::EulerA,
+            "Heun" => A1111Sampler::Heun,
+            "LMS" => A1111Sampler::LMS,
+            "DDIM" => A1111Sampler::DDIM,
+            "UniPC" => A1111Sampler::UniPC,
+            "DPM++ 2M" => A1111Sampler::DPMPP2M,
+            "DPM++ SDE" => A1111Sampler::DPMPPSDE,
+            "DPM++ 2M SDE" => A1111Sampler::DPMPP2MSDE,
+            other => A1111Sampler::Custom(other.to_owned()),
+        })
+    }
+}
+
+/// Second, upscaled pass of a txt2img generation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HiresFix {
+    pub scale: f64,
+    pub upscaler: String,
+    /// Steps of the second pass; the first pass's count when unset.
+    pub second_pass_steps: Option<u64>,
+    pub denoising_strength: f64,
+}
+
+/// One ControlNet unit, sent in `alwayson_scripts.controlnet.args`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ControlNetUnit {
+    pub enabled: bool,
+    /// Preprocessor, such as `canny` or `depth_midas`.
+    pub module: String,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInput>,
+    pub weight: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guidance_start: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guidance_end: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pixel_perfect: Option<bool>,
+    /// `Balanced`, `My prompt is more important` or `ControlNet is more important`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_mode: Option<String>,
+}
+
+impl ControlNetUnit {
+    pub fn new(module: String, model: String, image: ImageInput) -> Self {
+        Self {
+            enabled: true,
+            module,
+            model,
+            image: Some(image),
+            weight: 1.0,
+            guidance_start: None,
+            guidance_end: None,
+            pixel_perfect: None,
+            control_mode: None,
+        }
+    }
+
+    pub fn with_weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn with_guidance(mut self, start: f64, end: f64) -> Self {
+        self.guidance_start = Some(start);
+        self.guidance_end = Some(end);
+        self
+    }
+
+    pub fn with_pixel_perfect(mut self, pixel_perfect: bool) -> Self {
+        self.pixel_perfect = Some(pixel_perfect);
+        self
+    }
+
+    pub fn with_control_mode(mut self, control_mode: String) -> Self {
+        self.control_mode = Some(control_mode);
+        self
+    }
+}
+
+/// The `alwayson_scripts` of a payload. ControlNet units are kept typed and only turned
+/// into `controlnet.args` when the payload is serialized.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(from = "Map<String, Value>")]
+pub(crate) struct AlwaysOnScripts {
+    controlnet: Vec<ControlNetUnit>,
+    scripts: Map<String, Value>,
+}
+
+impl AlwaysOnScripts {
+    pub fn is_empty(&self) -> bool {
+        self.controlnet.is_empty() && self.scripts.is_empty()
+    }
+}
+
+impl From<Map<String, Value>> for AlwaysOnScripts {
+    fn from(scripts: Map<String, Value>) -> Self {
+        Self { controlnet: Vec::new(), scripts }
+    }
+}
+
+impl Serialize for AlwaysOnScripts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.controlnet.is_empty() && self.scripts.contains_key("controlnet") {
+            return Err(S::Error::custom("ControlNet units can't be combined with a `controlnet` script"));
+        }
+        let mut map = serializer.serialize_map(None)?;
+        for (name, script) in &self.scripts {
+            map.serialize_entry(name, script)?;
+        }
+        if !self.controlnet.is_empty() {
+            map.serialize_entry("controlnet", &json!({ "args": &self.controlnet }))?;
+        }
+        map.end()
+    }
+}
+
+/// Fields txt2img and img2img share.
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+pub struct A1111Params {
+    pub(crate) prompt: Option<String>,
+    pub(crate) negative_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub(crate) styles: Vec<String>,
+    pub(crate) seed: Option<i64>,
+    pub(crate) sampler_name: Option<String>,
+    pub(crate) scheduler: Option<String>,
+    pub(crate) steps: Option<u64>,
+    pub(crate) cfg_scale: Option<f64>,
+    pub(crate) width: Option<u64>,
+    pub(crate) height: Option<u64>,
+    pub(crate) batch_size: Option<u64>,
+    pub(crate) n_iter: Option<u64>,
+    pub(crate) denoising_strength: Option<f64>,
+    /// Web UI settings applied to this generation only, such as `sd_model_checkpoint`.
+    #[serde(skip_serializing_if = "Map::is_empty", default)]
+    pub(crate) override_settings: Map<String, Value>,
+    #[serde(skip_serializing_if = "AlwaysOnScripts::is_empty", default)]
+    pub(crate) alwayson_scripts: AlwaysOnScripts,
+}
+
+/// Access to the shared fields of txt2img and img2img payloads.
+pub trait A1111ParamsCore {
+    fn common(&self) -> &A1111Params;
+    fn common_mut(&mut self) -> &mut A1111Params;
+}
+
+pub trait A1111ParamBuilderTrait: A1111ParamsCore + Sized {
+    fn with_prompt(mut self, prompt: String) -> Self {
+        self.common_mut().prompt = Some(prompt);
+        self
+    }
+
+    fn with_negative_prompt(mut self, negative_prompt: String) -> Self {
+        self.common_mut().negative_prompt = Some(negative_prompt);
+        self
+    }
+
+    fn with_style(mut self, style: String) -> Self {
+        self.common_mut().styles.push(style);
+        self
+    }
+
+    fn with_seed(mut self, seed: i64) -> Self {
+        self.common_mut().seed = Some(seed);
+        self
+    }
+
+    /// Reuses the seed of a previous output to reproduce it. Fails if the output has
+    /// no seed, rather than silently rendering with a random one.
+    fn with_seed_from<O: DiffusionOutputFetch>(self, output: &O) -> Result<Self, Error> {
+        let seed = output.seed().ok_or(Error::msg("Output has no seed to reuse."))?;
+        Ok(self.with_seed(seed))
+    }
+
+    fn with_sampler(mut self, sampler: A1111Sampler) -> Self {
+        self.common_mut().sampler_name = Some(sampler.to_string());
+        self
+    }
+
+    /// Noise schedule, such as `Karras`, on web UI versions that set it apart from the sampler.
+    fn with_scheduler(mut self, scheduler: String) -> Self {
+        self.common_mut().scheduler = Some(scheduler);
+        self
+    }
+
+    fn with_steps(mut self, steps: u64) -> Self {
+        self.common_mut().steps = Some(steps);
+        self
+    }
+
+    fn with_cfg_scale(mut self, cfg_scale: f64) -> Self {
+        self.common_mut().cfg_scale = Some(cfg_scale);
+        self
+    }
+
+    fn with_resolution(mut self, width: u64, height: u64) -> Self {
+        self.common_mut().width = Some(width);
+        self.common_mut().height = Some(height);
+        self
+    }
+
+    /// `batch_size` images per pass, `n_iter` passes.
+    fn with_batch(mut self, batch_size: u64, n_iter: u64) -> Self {
+        self.common_mut().batch_size = Some(batch_size);
+        self.common_mut().n_iter = Some(n_iter);
+        self
+    }
+
+    fn with_override_setting(mut self, key: String, value: Value) -> Self {
+        self.common_mut().override_settings.insert(key, value);
+        self
+    }
+
+    fn with_controlnet(mut self, unit: ControlNetUnit) -> Self {
+        self.common_mut().alwayson_scripts.controlnet.push(unit);
+        self
+    }
+
+    /// Any other always-on script, with its positional `args`.
+    fn with_script(mut self, name: String, args: Vec<Value>) -> Self {
+        self.common_mut().alwayson_scripts.scripts.insert(name, json!({ "args": args }));
+        self
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+pub struct A1111Txt2ImgParams {
+    #[serde(flatten)]
+    common: A1111Params,
+    enable_hr: Option<bool>,
+    hr_scale: Option<f64>,
+    hr_upscaler: Option<String>,
+    hr_second_pass_steps: Option<u64>,
+}
+
+impl A1111Txt2ImgParams {
+    pub fn with_hires_fix(mut self, hires: HiresFix) -> Self {
+        self.enable_hr = Some(true);
+        self.hr_scale = Some(hires.scale);
+        self.hr_upscaler = Some(hires.upscaler);
+        self.hr_second_pass_steps = hires.second_pass_steps;
+        self.common.denoising_strength = Some(hires.denoising_strength);
+        self
+    }
+
+    pub fn build(self) -> A1111Txt2ImgParams {
+        self
+    }
+}
+
+impl A1111ParamsCore for A1111Txt2ImgParams {
+    fn common(&self) -> &A1111Params {
+        &self.common
+    }
+
+    fn common_mut(&mut self) -> &mut A1111Params {
+        &mut self.common
+    }
+}
+
+impl A1111ParamBuilderTrait for A1111Txt2ImgParams {}
+
+impl A1111Payload for A1111Txt2ImgParams {
+    const METHOD: &'static str = "POST";
+    const ENDPOINT: &'static str = "/sdapi/v1/txt2img";
+    type Output = A1111ImageOutput;
+
+    /// Hires fix scales the first-pass size, so the final image is what's checked.
+    fn validate(&self) -> Result<(), Error> {
+        validate_resolution(self.common.width, self.common.height, A1111_MAX_PIXELS)?;
+        let scale = self.hr_scale.filter(|_| self.enable_hr == Some(true)).unwrap_or(1.0);
+        if let (Some(width), Some(height)) = (self.common.width, self.common.height) {
+            let pixels = ((width as f64) * scale) * ((height as f64) * scale);
+            if pixels > (A1111_MAX_PIXELS as f64) {
+                return Err(
+                    Error::msg(std::format!("Hires fix output is over the {} pixel limit", A1111_MAX_PIXELS))
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(no_new)]
+pub struct A1111Img2ImgParams {
+    #[serde(flatten)]
+    common: A1111Params,
+    init_images: Vec<ImageInput>,
+    mask: Option<ImageInput>,
+    mask_blur: Option<u64>,
+    /// 0 fill, 1 original, 2 latent noise, 3 latent nothing.
+    inpainting_fill: Option<u64>,
+    inpaint_full_res: Option<bool>,
+    /// 0 just resize, 1 crop and resize, 2 resize and fill.
+    resize_mode: Option<u64>,
+}
+
+impl A1111Img2ImgParams {
+    pub fn new(init_image: ImageInput) -> Self {
+        Self {
+            init_images: vec![init_image],
+            ..Default::default()
+        }
+    }
+
+    pub fn with_denoising_strength(mut self, denoising_strength: f64) -> Self {
+        self.common.denoising_strength = Some(denoising_strength);
+        self
+    }
+
+    pub fn with_mask(mut self, mask: ImageInput, mask_blur: u64) -> Self {
+        self.mask = Some(mask);
+        self.mask_blur = Some(mask_blur);
+        self
+    }
+
+    pub fn with_inpainting_fill(mut self, inpainting_fill: u64) -> Self {
+        self.inpainting_fill = Some(inpainting_fill);
+        self
+    }
+
+    pub fn with_inpaint_full_res(mut self, inpaint_full_res: bool) -> Self {
+        self.inpaint_full_res = Some(inpaint_full_res);
+        self
+    }
+
+    pub fn with_resize_mode(mut self, resize_mode: u64) -> Self {
+        self.resize_mode = Some(resize_mode);
+        self
+    }
+
+    pub fn build(self) -> A1111Img2ImgParams {
+        self
+    }
+}
+
+impl A1111ParamsCore for A1111Img2ImgParams {
+    fn common(&self) -> &A1111Params {
+        &self.common
+    }
+
+    fn common_mut(&mut self) -> &mut A1111Params {
+        &mut self.common
+    }
+}
+
+impl A1111ParamBuilderTrait for A1111Img2ImgParams {}
+
+impl A1111Payload for A1111Img2ImgParams {
+    const METHOD: &'static str = "POST";
+    const ENDPOINT: &'static str = "/sdapi/v1/img2img";
+    type Output = A1111ImageOutput;
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.init_images.is_empty() {
+            return Err(Error::msg("img2img needs an init image"));
+        }
+        validate_resolution(self.common.width, self.common.height, A1111_MAX_PIXELS)
+    }
+}
+
+/// Web UI settings to change for every following generation, such as the loaded checkpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct A1111Options(Map<String, Value>);
+
+impl A1111Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_checkpoint(self, checkpoint: String) -> Self {
+        self.with_option("sd_model_checkpoint".to_owned(), Value::String(checkpoint))
+    }
+
+    pub fn with_clip_skip(self, clip_skip: u64) -> Self {
+        self.with_option("CLIP_stop_at_last_layers".to_owned(), Value::from(clip_skip))
+    }
+
+    pub fn with_option(mut self, key: String, value: Value) -> Self {
+        self.0.insert(key, value);
+        self
+    }
+}
+
+impl RunpodParams for A1111Options {}
+
+impl A1111Payload for A1111Options {
+    const METHOD: &'static str = "POST";
+    const ENDPOINT: &'static str = "/sdapi/v1/options";
+    type Output = Value;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct A1111ImageOutput {
+    /// Base64 images; with ControlNet, the detected maps follow the generated images.
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub parameters: Value,
+    /// Generation details as a JSON string.
+    #[serde(default)]
+    pub info: String,
+}
+
+impl A1111ImageOutput {
+    pub fn info(&self) -> Result<Value, Error> {
+        serde_json::from_str(&self.info).map_err(|x| x.into())
+    }
+
+    /// The seed of every image in the batch.
+    pub fn all_seeds(&self) -> Vec<i64> {
+        self.info()
+            .ok()
+            .and_then(|info| serde_json::from_value(info["all_seeds"].clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl DiffusionOutputFetch for A1111ImageOutput {
+    fn images(&self) -> Vec<&str> {
+        self.images
+            .iter()
+            .map(|i| i.as_str())
+            .collect()
+    }
+
+    fn seed(&self) -> Option<i64> {
+        self.info()
+            .ok()
+            .and_then(|info| info["seed"].as_i64())
+    }
+
+    /// `all_seeds` from the info, when it has one seed per image.
+    fn seeds(&self) -> Vec<Option<i64>> {
+        let all_seeds = self.all_seeds();
+        if all_seeds.len() == self.images.len() {
+            all_seeds.into_iter().map(Some).collect()
+        } else {
+            self.images
+                .iter()
+                .map(|_| self.seed())
+                .collect()
+        }
+    }
+}
+
+#[async_trait]
+impl<P> RunpodClientAPI<P, Result<A1111Result<P::Output>, Error>> for RunpodClient<A1111> where P: A1111Payload {
+    async fn request(&self, params: P) -> Result<A1111Result<P::Output>, Error> {
+        params.validate()?;
+        let input = json!({
+            "api": { "method": P::METHOD, "endpoint": P::ENDPOINT },
+            "payload": serde_json::to_value(&params)?
+        });
+        let queued = job::submit(&self.api_base, &self.machine_id, &self.api_key, &input).await?;
+        job::wait(&self.api_base, &self.machine_id, &self.api_key, &queued.id, self.poll_time).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::{ diffusion::DiffusionOutputFetch, image::ImageInput },
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{
+        A1111,
+        A1111Img2ImgParams,
+        A1111Options,
+        A1111ParamBuilderTrait,
+        A1111Payload,
+        A1111Result,
+        A1111Sampler,
+        A1111ImageOutput,
+        A1111Txt2ImgParams,
+        ControlNetUnit,
+        HiresFix,
+    };
+
+    const TXT2IMG: &str = include_str!("../../tests/fixtures/a1111/txt2img.json");
+
+    #[test]
+    fn test_txt2img_payload() {
+        let params = A1111Txt2ImgParams::new()
+            .with_prompt("a castle on a hill".to_owned())
+            .with_sampler(A1111Sampler::DPMPP2M)
+            .with_scheduler("Karras".to_owned())
+            .with_steps(25)
+            .with_resolution(512, 768)
+            .with_override_setting("sd_model_checkpoint".to_owned(), json!("dreamshaper_8"))
+            .with_controlnet(
+                ControlNetUnit::new(
+                    "canny".to_owned(),
+                    "control_v11p_sd15_canny".to_owned(),
+                    ImageInput::from_url("https://example.com/edges.png").unwrap()
+                ).with_weight(0.8)
+            )
+            .with_hires_fix(HiresFix {
+                scale: 2.0,
+                upscaler: "Latent".to_owned(),
+                second_pass_steps: Some(10),
+                denoising_strength: 0.5,
+            })
+            .build();
+
+        assert!(params.validate().is_ok());
+        assert_eq!(
+            serde_json::to_value(&params).unwrap(),
+            json!({
+                "prompt": "a castle on a hill",
+                "sampler_name": "DPM++ 2M",
+                "scheduler": "Karras",
+                "steps": 25,
+                "width": 512,
+                "height": 768,
+                "denoising_strength": 0.5,
+                "override_settings": { "sd_model_checkpoint": "dreamshaper_8" },
+                "alwayson_scripts": {
+                    "controlnet": {
+                        "args": [{
+                            "enabled": true,
+                            "module": "canny",
+                            "model": "control_v11p_sd15_canny",
+                            "image": "https://example.com/edges.png",
+                            "weight": 0.8
+                        }]
+                    }
+                },
+                "enable_hr": true,
+                "hr_scale": 2.0,
+                "hr_upscaler": "Latent",
+                "hr_second_pass_steps": 10
+            })
+        );
+
+        let round_trip: A1111Txt2ImgParams = serde_json::from_value(serde_json::to_value(&params).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&round_trip).unwrap(), serde_json::to_value(&params).unwrap());
+
+        let clashing = params.clone().with_script("controlnet".to_owned(), vec![]);
+        assert!(serde_json::to_value(&clashing).is_err());
+
+        let too_big = params.with_resolution(1536, 1536);
+        assert!(too_big.validate().is_err());
+    }
+
+    #[test]
+    fn test_img2img_payload_and_output() {
+        let output: A1111Result<A1111ImageOutput> = serde_json::from_str(TXT2IMG).unwrap();
+        let output = output.output.unwrap();
+        assert_eq!(output.seed(), Some(1234567890));
+        assert_eq!(output.all_seeds(), vec![1234567890, 1234567891]);
+        assert_eq!(output.images().len(), 2);
+
+        let params = A1111Img2ImgParams::new(ImageInput::from_url("https://example.com/in.png").unwrap())
+            .with_denoising_strength(0.4)
+            .with_seed_from(&output)
+            .unwrap()
+            .with_resize_mode(1)
+            .build();
+        let body = serde_json::to_value(&params).unwrap();
+        assert_eq!(body["init_images"], json!(["https://example.com/in.png"]));
+        assert_eq!(body["seed"], 1234567890);
+        assert_eq!(body["denoising_strength"], 0.4);
+        assert!(A1111Img2ImgParams::default().validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_api_wrapper() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/a1111-endpoint/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/a1111-endpoint/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(TXT2IMG))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(A1111)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("a1111-endpoint".to_owned())
+            .build();
+        let result = client.request(A1111Txt2ImgParams::new().with_prompt("a castle".to_owned())).await.unwrap();
+        let images = result.output.unwrap().fetch_all().await.unwrap();
+        assert_eq!(images[0][..4], [0x89, b'P', b'N', b'G']);
+
+        let options: A1111Result<Value> = client
+            .request(A1111Options::new().with_checkpoint("dreamshaper_8".to_owned())).await
+            .unwrap();
+        assert!(options.output.is_some());
+
+        let sent: Vec<Value> = server
+            .received_requests().await
+            .unwrap()
+            .iter()
+            .filter(|r| r.url.path().ends_with("/run"))
+            .map(|r| r.body_json::<Value>().unwrap())
+            .collect();
+        assert_eq!(sent[0]["input"]["api"], json!({ "method": "POST", "endpoint": "/sdapi/v1/txt2img" }));
+        assert_eq!(sent[1]["input"]["api"]["endpoint"], "/sdapi/v1/options");
+        assert_eq!(sent[1]["input"]["payload"], json!({ "sd_model_checkpoint": "dreamshaper_8" }));
+    }
+}
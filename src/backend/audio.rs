@@ -0,0 +1,328 @@
+use std::{ fs, path::{ Path, PathBuf } };
+
+use anyhow::Error;
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use reqwest::Url;
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
+
+use super::image::MAX_PAYLOAD_BYTES;
+
+/// Audio for a worker to process: a URL it downloads itself, or a file sent inline as base64.
+/// Serialized as the bare string either way.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioInput {
+    Url(String),
+    Base64(String),
+}
+
+impl Serialize for AudioInput {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AudioInput {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Ok(AudioInput::from_url(&value).unwrap_or(AudioInput::Base64(value)))
+    }
+}
+
+impl AudioInput {
+    pub fn from_url(url: &str) -> Result<Self, Error> {
+        let parsed = Url::parse(url)?;
+        match parsed.scheme() {
+            "http" | "https" => Ok(AudioInput::Url(url.to_owned())),
+            scheme => Err(Error::msg(std::format!("Unsupported audio URL scheme '{}'", scheme))),
+        }
+    }
+
+    /// Encodes `bytes`, failing if they would push the request over the payload limit.
+    /// Longer recordings have to be hosted and passed by URL.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let encoded = STANDARD.encode(bytes);
+        if encoded.len() > MAX_PAYLOAD_BYTES {
+            return Err(
+                Error::msg(
+                    std::format!(
+                        "Encoded audio is {} bytes, over the {} byte limit; pass it by URL instead",
+                        encoded.len(),
+                        MAX_PAYLOAD_BYTES
+                    )
+                )
+            );
+        }
+        Ok(AudioInput::Base64(encoded))
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            AudioInput::Url(url) => url.as_str(),
+            AudioInput::Base64(data) => data.as_str(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    Mp3,
+}
+
+impl AudioFormat {
+    /// Sniffs the format from the first bytes of a file.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(AudioFormat::Wav),
+            [b'I', b'D', b'3', ..] => Some(AudioFormat::Mp3),
+            [0xff, b, ..] if b & 0xe0 == 0xe0 => Some(AudioFormat::Mp3),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Mp3 => "mp3",
+        }
+    }
+}
+
+/// What the header of an audio file says about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioInfo {
+    pub format: AudioFormat,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Seconds. Estimated from the bitrate for MP3s without a frame count.
+    pub duration: f64,
+}
+
+impl AudioInfo {
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        match AudioFormat::detect(bytes) {
+            Some(AudioFormat::Wav) => parse_wav(bytes),
+            Some(AudioFormat::Mp3) => parse_mp3(bytes),
+            None => Err(Error::msg("Unrecognised audio format")),
+        }
+    }
+}
+
+fn u16_le(bytes: &[u8], at: usize) -> Result<u16, Error> {
+    Ok(u16::from_le_bytes(bytes.get(at..at + 2).ok_or(Error::msg("Truncated audio header"))?.try_into()?))
+}
+
+fn u32_le(bytes: &[u8], at: usize) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(bytes.get(at..at + 4).ok_or(Error::msg("Truncated audio header"))?.try_into()?))
+}
+
+fn parse_wav(bytes: &[u8]) -> Result<AudioInfo, Error> {
+    let mut format: Option<(u16, u32, u32)> = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = u32_le(bytes, offset + 4)? as usize;
+        let body = offset + 8;
+        match id {
+            b"fmt " => {
+                format = Some((u16_le(bytes, body + 2)?, u32_le(bytes, body + 4)?, u32_le(bytes, body + 8)?));
+            }
+            b"data" => {
+                let (channels, sample_rate, byte_rate) = format.ok_or(
+                    Error::msg("WAV data chunk comes before its fmt chunk")
+                )?;
+                // Streamed WAVs leave the size as 0 or u32::MAX; fall back to what's there.
+                let available = bytes.len() - body;
+                let size = if size == 0 || size > available { available } else { size };
+                return Ok(AudioInfo {
+                    format: AudioFormat::Wav,
+                    sample_rate,
+                    channels,
+                    duration: if byte_rate == 0 { 0.0 } else { (size as f64) / (byte_rate as f64) },
+                });
+            }
+            _ => {}
+        }
+        offset = body + size + (size % 2);
+    }
+    Err(Error::msg("WAV file has no data chunk"))
+}
+
+const MP3_BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MP3_BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+fn parse_mp3(bytes: &[u8]) -> Result<AudioInfo, Error> {
+    // Skip an ID3v2 tag; its size is stored 7 bits per byte.
+    let mut offset = 0;
+    if bytes.starts_with(b"ID3") && bytes.len() >= 10 {
+        let size = bytes[6..10].iter().fold(0usize, |acc, b| (acc << 7) | ((*b & 0x7f) as usize));
+        offset = 10 + size + (if bytes[5] & 0x10 != 0 { 10 } else { 0 });
+    }
+    let header = bytes.get(offset..offset + 4).ok_or(Error::msg("MP3 has no frame after its tag"))?;
+    if header[0] != 0xff || header[1] & 0xe0 != 0xe0 || (header[1] >> 1) & 3 != 1 {
+        return Err(Error::msg("Only MPEG Layer III audio is supported"));
+    }
+
+    let version = (header[1] >> 3) & 3;
+    let mpeg1 = version == 3;
+    let bitrate = *(if mpeg1 { &MP3_BITRATES_V1 } else { &MP3_BITRATES_V2 })
+        .get((header[2] >> 4) as usize)
+        .ok_or(Error::msg("Invalid MP3 bitrate"))?;
+    let base_rate = *[44100, 48000, 32000].get(((header[2] >> 2) & 3) as usize).ok_or(Error::msg("Invalid MP3 sample rate"))?;
+    let sample_rate = match version {
+        3 => base_rate,
+        2 => base_rate / 2,
+        _ => base_rate / 4,
+    };
+    let mono = header[3] >> 6 == 3;
+    let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+
+    // A Xing or Info header in the first frame carries the frame count.
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) => 17,
+        (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = offset + 4 + side_info;
+    let frames = match bytes.get(xing..xing + 12) {
+        Some(tag) if (&tag[..4] == b"Xing" || &tag[..4] == b"Info") && tag[7] & 1 == 1 => {
+            Some(u32::from_be_bytes(tag[8..12].try_into()?))
+        }
+        _ => None,
+    };
+
+    let duration = match frames {
+        Some(frames) => ((frames as f64) * (samples_per_frame as f64)) / (sample_rate as f64),
+        None if bitrate > 0 => (((bytes.len() - offset) * 8) as f64) / ((bitrate * 1000) as f64),
+        None => 0.0,
+    };
+    Ok(AudioInfo {
+        format: AudioFormat::Mp3,
+        sample_rate,
+        channels: if mono { 1 } else { 2 },
+        duration,
+    })
+}
+
+/// Audio as a worker returned it: hosted at a URL or inline in the response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioOutput {
+    Url(Url),
+    Inline(Vec<u8>),
+}
+
+impl AudioOutput {
+    /// Recognises http(s) URLs, `data:` URIs and raw base64.
+    pub fn parse(audio: &str) -> Result<Self, Error> {
+        let audio = audio.trim();
+        if let Some(data) = audio.strip_prefix("data:") {
+            let (_, payload) = data.split_once(";base64,").ok_or(Error::msg("Malformed data URI in audio output"))?;
+            return Ok(AudioOutput::Inline(STANDARD.decode(payload)?));
+        }
+        if let Ok(url) = Url::parse(audio) {
+            if matches!(url.scheme(), "http" | "https") {
+                return Ok(AudioOutput::Url(url));
+            }
+        }
+        STANDARD.decode(audio)
+            .map(AudioOutput::Inline)
+            .map_err(|_| Error::msg("Audio output is neither a URL nor base64"))
+    }
+
+    pub async fn load(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            AudioOutput::Inline(bytes) => Ok(bytes.clone()),
+            AudioOutput::Url(url) => {
+                reqwest::Client
+                    ::new()
+                    .get(url.clone())
+                    .send().await?
+                    .error_for_status()?
+                    .bytes().await
+                    .map(|x| x.to_vec())
+                    .map_err(|x| x.into())
+            }
+        }
+    }
+
+    /// Writes the audio to `path`, adding a `.wav` or `.mp3` extension if it has none,
+    /// and returns where it went with what its header says.
+    pub async fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(PathBuf, AudioInfo), Error> {
+        let bytes = self.load().await?;
+        let info = AudioInfo::parse(&bytes)?;
+        let path = path.as_ref();
+        let target = match path.extension() {
+            Some(_) => path.to_path_buf(),
+            None => path.with_extension(info.format.extension()),
+        };
+        fs::write(&target, &bytes)?;
+        Ok((target, info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ AudioFormat, AudioInfo, AudioOutput };
+
+    /// A mono 16-bit WAV of `samples` silent samples.
+    fn wav(sample_rate: u32, samples: u32) -> Vec<u8> {
+        let data = samples * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data.to_le_bytes());
+        wav.resize(wav.len() + (data as usize), 0);
+        wav
+    }
+
+    #[test]
+    fn test_wav_header() {
+        let info = AudioInfo::parse(&wav(16000, 24000)).unwrap();
+        assert_eq!(info, AudioInfo { format: AudioFormat::Wav, sample_rate: 16000, channels: 1, duration: 1.5 });
+    }
+
+    #[test]
+    fn test_mp3_header() {
+        // MPEG-1 Layer III, 128 kbps, 44.1 kHz, stereo: 417 byte frames.
+        let frame = {
+            let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+            frame.resize(417, 0);
+            frame
+        };
+        let cbr: Vec<u8> = frame.repeat(10);
+        let info = AudioInfo::parse(&cbr).unwrap();
+        assert_eq!((info.format, info.sample_rate, info.channels), (AudioFormat::Mp3, 44100, 2));
+        assert!((info.duration - 0.260625).abs() < 1e-9);
+
+        let mut tagged = b"ID3     
+".to_vec();
+        tagged.extend_from_slice(&[0; 10]);
+        let mut first = frame.clone();
+        first[36..48].copy_from_slice(b"Info      d");
+        tagged.extend_from_slice(&first);
+        let info = AudioInfo::parse(&tagged).unwrap();
+        assert!((info.duration - (100.0 * 1152.0) / 44100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_parse_outputs() {
+        assert_eq!(AudioOutput::parse("data:audio/wav;base64,UklGRg==").unwrap(), AudioOutput::Inline(b"RIFF".to_vec()));
+        assert_eq!(AudioOutput::parse("UklGRg==").unwrap(), AudioOutput::Inline(b"RIFF".to_vec()));
+        assert!(matches!(AudioOutput::parse("https://example.com/a.mp3").unwrap(), AudioOutput::Url(_)));
+        assert!(AudioOutput::parse("not audio!").is_err());
+    }
+}
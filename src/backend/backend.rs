@@ -1,12 +1,24 @@
 use std::time::Duration;
 
+use anyhow::Error;
 use async_trait::async_trait;
 use reqwest::Url;
+use serde::ser::SerializeMap;
+
+pub use runpod_client_derive::RunpodParams;
 
 pub trait RunpodBackend: {}
 
 pub trait RunpodParams: {}
 
+/// Implemented by `#[derive(RunpodParams)]`: writes the fields that are set into a map,
+/// so params can be serialized and flattened into one another, and checks the ranges
+/// declared on them.
+pub trait ParamFields {
+    fn serialize_fields<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error>;
+    fn check_ranges(&self) -> Result<(), Error>;
+}
+
 #[async_trait]
 pub trait RunpodRequest<R, P, E>: {
     async fn queue_job(
@@ -21,3 +33,57 @@ pub trait RunpodRequest<R, P, E>: {
         poll_time: Duration,
     ) -> Result<R, E>;
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::{ ParamFields, RunpodParams };
+
+    #[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+    struct Inner {
+        #[runpod(range(min = 1))]
+        steps: Option<u64>,
+    }
+
+    #[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+    #[runpod(builder = OuterBuilder)]
+    struct Outer {
+        #[serde(flatten)]
+        #[runpod(skip)]
+        inner: Inner,
+        prompt: String,
+        #[serde(rename = "image_url")]
+        image: Option<String>,
+        #[runpod(setter = with_lora)]
+        lora: Option<String>,
+        #[runpod(setter = with_lora, range(min = 0.0, max = 1.0, exclusive_min))]
+        lora_scale: Option<f64>,
+        #[serde(skip)]
+        batch_size: Option<usize>,
+    }
+
+    #[test]
+    fn test_derived_params() {
+        let mut params = Outer::new()
+            .with_prompt("a cat".to_owned())
+            .with_image("https://example.com/cat.png".to_owned())
+            .with_lora("pixel-art".to_owned(), 0.5)
+            .with_batch_size(4)
+            .build();
+        assert_eq!(
+            serde_json::to_value(&params).unwrap(),
+            json!({ "prompt": "a cat", "image_url": "https://example.com/cat.png", "lora": "pixel-art", "lora_scale": 0.5 })
+        );
+        assert!(params.check_ranges().is_ok());
+
+        params.inner.steps = Some(0);
+        assert_eq!(params.check_ranges().unwrap_err().to_string(), "steps must be at least 1");
+        params.inner.steps = Some(20);
+        assert_eq!(serde_json::to_value(&params).unwrap()["steps"], 20);
+
+        let params = params.with_lora("pixel-art".to_owned(), 0.0);
+        assert_eq!(params.check_ranges().unwrap_err().to_string(), "lora_scale must be in (0.0, 1.0]");
+    }
+}
@@ -0,0 +1,358 @@
+use std::{ fs, path::Path, str::FromStr };
+
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ Map, Value };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{
+    backend::{ RunpodBackend, RunpodParams },
+    download::{ download_all, Download, DownloadOptions },
+    image::{ ImageInput, ImageOutput },
+    job::{ self, JobResult },
+};
+
+pub struct ComfyUI;
+
+impl RunpodBackend for ComfyUI {}
+
+/// A node of a workflow, by its id in the graph or by the title shown in the editor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComfyNode {
+    Id(String),
+    Title(String),
+}
+
+impl ComfyNode {
+    pub fn id(id: &str) -> Self {
+        ComfyNode::Id(id.to_owned())
+    }
+
+    pub fn title(title: &str) -> Self {
+        ComfyNode::Title(title.to_owned())
+    }
+}
+
+/// A ComfyUI workflow in API format, as written by "Save (API Format)" in the editor.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct ComfyWorkflow(Map<String, Value>);
+
+impl ComfyWorkflow {
+    pub fn from_value(workflow: Value) -> Result<Self, Error> {
+        let nodes = match workflow {
+            Value::Object(nodes) => nodes,
+            _ => {
+                return Err(Error::msg("Workflow must be a JSON object of nodes"));
+            }
+        };
+        if nodes.contains_key("nodes") && nodes.contains_key("links") {
+            return Err(Error::msg("Workflow is in editor format; export it with \"Save (API Format)\""));
+        }
+        for (id, node) in nodes.iter() {
+            if node.get("class_type").is_none() || !node.get("inputs").is_some_and(|i| i.is_object()) {
+                return Err(Error::msg(std::format!("Workflow node {} has no class_type or inputs", id)));
+            }
+        }
+        Ok(ComfyWorkflow(nodes))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Id of `node`. Titles must match exactly one node.
+    pub fn resolve(&self, node: &ComfyNode) -> Result<String, Error> {
+        match node {
+            ComfyNode::Id(id) if self.0.contains_key(id) => Ok(id.clone()),
+            ComfyNode::Id(id) => Err(Error::msg(std::format!("Workflow has no node {}", id))),
+            ComfyNode::Title(title) => {
+                let matches: Vec<&String> = self.0
+                    .iter()
+                    .filter(|(_, n)| n["_meta"]["title"].as_str() == Some(title.as_str()))
+                    .map(|(id, _)| id)
+                    .collect();
+                match matches.as_slice() {
+                    [id] => Ok((*id).clone()),
+                    [] => Err(Error::msg(std::format!("Workflow has no node titled '{}'", title))),
+                    _ => Err(Error::msg(std::format!("Several workflow nodes are titled '{}'", title))),
+                }
+            }
+        }
+    }
+
+    pub fn input(&self, node: &ComfyNode, input: &str) -> Option<&Value> {
+        let id = self.resolve(node).ok()?;
+        self.0[&id]["inputs"].get(input)
+    }
+
+    pub fn set_input(&mut self, node: &ComfyNode, input: &str, value: Value) -> Result<(), Error> {
+        let id = self.resolve(node)?;
+        let inputs = self.0
+            .get_mut(&id)
+            .and_then(|n| n.get_mut("inputs"))
+            .and_then(|i| i.as_object_mut())
+            .ok_or(Error::msg(std::format!("Workflow node {} has no inputs", id)))?;
+        inputs.insert(input.to_owned(), value);
+        Ok(())
+    }
+
+    /// Sets the `text` of a prompt node such as `CLIPTextEncode`.
+    pub fn set_text(&mut self, node: &ComfyNode, text: &str) -> Result<(), Error> {
+        self.set_input(node, "text", Value::from(text))
+    }
+
+    /// Sets the seed of a sampler, as `noise_seed` on nodes that name it so.
+    pub fn set_seed(&mut self, node: &ComfyNode, seed: u64) -> Result<(), Error> {
+        let input = if self.input(node, "noise_seed").is_some() { "noise_seed" } else { "seed" };
+        self.set_input(node, input, Value::from(seed))
+    }
+
+    pub fn set_steps(&mut self, node: &ComfyNode, steps: u64) -> Result<(), Error> {
+        self.set_input(node, "steps", Value::from(steps))
+    }
+}
+
+impl FromStr for ComfyWorkflow {
+    type Err = Error;
+
+    fn from_str(workflow: &str) -> Result<Self, Self::Err> {
+        Self::from_value(serde_json::from_str(workflow)?)
+    }
+}
+
+/// An image uploaded to the worker's input folder before the workflow runs, for
+/// `LoadImage` nodes to refer to by `name`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ComfyInputImage {
+    pub name: String,
+    pub image: ImageInput,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(builder = ComfyUIParamBuilderTrait)]
+pub struct ComfyUIParams {
+    workflow: ComfyWorkflow,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[runpod(skip)]
+    images: Vec<ComfyInputImage>,
+}
+
+impl ComfyUIParams {
+    pub fn workflow_mut(&mut self) -> &mut ComfyWorkflow {
+        &mut self.workflow
+    }
+
+    /// Uploads `image` as `name` for the workflow's `LoadImage` nodes.
+    pub fn with_image(mut self, name: String, image: ImageInput) -> Self {
+        self.images.push(ComfyInputImage { name, image });
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ComfyOutputImage {
+    pub filename: Option<String>,
+    /// Base64 or a URL, when the worker uploads its outputs to a bucket.
+    pub data: String,
+}
+
+/// Images a workflow produced. Older workers return a single `message` with one image;
+/// newer ones return a list of `images`. Both are accepted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(from = "ComfyUIOutputRepr")]
+pub struct ComfyUIOutput {
+    pub images: Vec<ComfyOutputImage>,
+    pub status: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ComfyImageRepr {
+    #[serde(default)]
+    filename: Option<String>,
+    data: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ComfyMessageRepr {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct ComfyUIOutputRepr {
+    #[serde(default)]
+    message: Option<ComfyMessageRepr>,
+    #[serde(default)]
+    images: Vec<ComfyImageRepr>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl From<ComfyUIOutputRepr> for ComfyUIOutput {
+    fn from(repr: ComfyUIOutputRepr) -> Self {
+        let mut images: Vec<ComfyOutputImage> = repr.images
+            .into_iter()
+            .map(|i| ComfyOutputImage { filename: i.filename, data: i.data })
+            .collect();
+        let legacy = match repr.message {
+            Some(ComfyMessageRepr::One(message)) => vec![message],
+            Some(ComfyMessageRepr::Many(messages)) => messages,
+            None => Vec::new(),
+        };
+        // Failed jobs on old workers put the error text in `message` too.
+        let (legacy, errors): (Vec<String>, Vec<String>) = legacy
+            .into_iter()
+            .partition(|m| ImageOutput::parse(m).is_ok());
+        images.extend(legacy.into_iter().map(|data| ComfyOutputImage { filename: None, data }));
+
+        ComfyUIOutput {
+            images,
+            status: repr.status,
+            error: repr.error.or(errors.into_iter().next()),
+        }
+    }
+}
+
+impl ComfyUIOutput {
+    pub async fn fetch_all(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut fetched = Vec::new();
+        for image in self.images.iter() {
+            fetched.push(ImageOutput::parse(&image.data)?.load().await?);
+        }
+        Ok(fetched)
+    }
+
+    /// Streams every image to disk, numbered like `DiffusionOutputFetch::download_to`.
+    pub async fn download_to(&self, path: &Path, options: &DownloadOptions) -> Result<Vec<Download>, Error> {
+        let images = self.images
+            .iter()
+            .map(|i| ImageOutput::parse(&i.data))
+            .collect::<Result<Vec<_>, Error>>()?;
+        download_all(images, path, options).await
+    }
+}
+
+pub type ComfyUIResult = JobResult<ComfyUIOutput>;
+
+#[async_trait]
+impl RunpodClientAPI<ComfyUIParams, Result<ComfyUIResult, Error>> for RunpodClient<ComfyUI> {
+    async fn request(&self, params: ComfyUIParams) -> Result<ComfyUIResult, Error> {
+        let queued = job::submit(&self.api_base, &self.machine_id, &self.api_key, &params).await?;
+        let result = job::wait::<ComfyUIOutput>(
+            &self.api_base,
+            &self.machine_id,
+            &self.api_key,
+            &queued.id,
+            self.poll_time
+        ).await?;
+        // The job completes even when the workflow fails inside ComfyUI.
+        if let Some(error) = result.output.as_ref().and_then(|o| o.error.as_ref()) {
+            return Err(Error::msg(std::format!("ComfyUI workflow failed: {}", error)));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::image::{ ImageEncoding, ImageInput },
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ ComfyNode, ComfyUI, ComfyUIOutput, ComfyUIParamBuilderTrait, ComfyUIParams, ComfyWorkflow };
+
+    const WORKFLOW: &str = include_str!("../../tests/fixtures/comfyui/workflow_api.json");
+    const PNG: &str = "iVBORw0KGgo=";
+
+    #[test]
+    fn test_patch_workflow() {
+        let mut workflow = WORKFLOW.parse::<ComfyWorkflow>().unwrap();
+        workflow.set_text(&ComfyNode::title("Positive Prompt"), "a red fox in snow").unwrap();
+        workflow.set_seed(&ComfyNode::id("3"), 42).unwrap();
+        workflow.set_steps(&ComfyNode::title("KSampler"), 30).unwrap();
+
+        assert_eq!(workflow.input(&ComfyNode::id("6"), "text"), Some(&json!("a red fox in snow")));
+        assert_eq!(workflow.input(&ComfyNode::id("3"), "seed"), Some(&json!(42)));
+        assert_eq!(workflow.input(&ComfyNode::id("3"), "steps"), Some(&json!(30)));
+        assert!(workflow.set_steps(&ComfyNode::title("Upscaler"), 30).is_err());
+        assert!(workflow.set_steps(&ComfyNode::id("99"), 30).is_err());
+
+        assert!(ComfyWorkflow::from_value(json!({ "nodes": [], "links": [] })).is_err());
+        assert!(ComfyWorkflow::from_value(json!({ "1": { "inputs": {} } })).is_err());
+    }
+
+    #[test]
+    fn test_both_output_formats() {
+        let legacy: ComfyUIOutput = serde_json::from_value(
+            json!({ "message": PNG, "status": "success" })
+        ).unwrap();
+        assert_eq!(legacy.images.len(), 1);
+        assert_eq!(legacy.error, None);
+
+        let current: ComfyUIOutput = serde_json::from_value(
+            json!({
+                "images": [
+                    { "filename": "ComfyUI_00001_.png", "type": "base64", "data": PNG },
+                    { "filename": "ComfyUI_00002_.png", "type": "s3_url", "data": "https://bucket.example.com/2.png" }
+                ]
+            })
+        ).unwrap();
+        assert_eq!(current.images[1].filename.as_deref(), Some("ComfyUI_00002_.png"));
+
+        let failed: ComfyUIOutput = serde_json::from_value(
+            json!({ "message": "Prompt outputs failed validation", "status": "error" })
+        ).unwrap();
+        assert!(failed.images.is_empty());
+        assert_eq!(failed.error.as_deref(), Some("Prompt outputs failed validation"));
+    }
+
+    #[tokio::test]
+    async fn test_request_with_input_image() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/comfy-endpoint/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/comfy-endpoint/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-1",
+                "status": "COMPLETED",
+                "output": { "images": [{ "filename": "ComfyUI_00001_.png", "type": "base64", "data": PNG }] }
+            })))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(ComfyUI)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("comfy-endpoint".to_owned())
+            .build();
+        let mut params = ComfyUIParams::new()
+            .with_workflow(WORKFLOW.parse::<ComfyWorkflow>().unwrap())
+            .with_image(
+                "input.png".to_owned(),
+                ImageInput::from_bytes(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a], ImageEncoding::Base64).unwrap()
+            );
+        params.workflow_mut().set_seed(&ComfyNode::title("KSampler"), 7).unwrap();
+
+        let result = client.request(params).await.unwrap();
+        let images = result.output.unwrap().fetch_all().await.unwrap();
+        assert_eq!(images[0][..4], [0x89, b'P', b'N', b'G']);
+
+        let sent = server.received_requests().await.unwrap();
+        let body = sent[0].body_json::<Value>().unwrap();
+        assert_eq!(body["input"]["workflow"]["3"]["inputs"]["seed"], 7);
+        assert_eq!(body["input"]["images"], json!([{ "name": "input.png", "image": PNG }]));
+    }
+}
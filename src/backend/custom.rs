@@ -0,0 +1,129 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ de::DeserializeOwned, Serialize };
+use serde_json::Value;
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{ backend::RunpodBackend, job::{ self, JobResult, JobState } };
+
+/// Any worker with a hand-written handler. The input is sent as-is under `input`, and the
+/// output is returned as a `Value` or whatever type the caller asks for. `machine_id` is
+/// the endpoint id.
+pub struct Custom;
+
+impl RunpodBackend for Custom {}
+
+pub type CustomResult<O = Value> = JobResult<O>;
+
+impl RunpodClient<Custom> {
+    /// Queues a job without waiting for it.
+    pub async fn submit<P: Serialize>(&self, input: &P) -> Result<JobState, Error> {
+        job::submit(&self.api_base, &self.machine_id, &self.api_key, input).await
+    }
+
+    /// Where the job is now, with its output if it has finished.
+    pub async fn status<O: DeserializeOwned>(&self, job_id: &str) -> Result<CustomResult<O>, Error> {
+        job::status(&self.api_base, &self.machine_id, &self.api_key, job_id).await
+    }
+
+    /// Polls a submitted job until it completes.
+    pub async fn wait<O: DeserializeOwned>(&self, job_id: &str) -> Result<CustomResult<O>, Error> {
+        job::wait(&self.api_base, &self.machine_id, &self.api_key, job_id, self.poll_time).await
+    }
+
+    pub async fn cancel(&self, job_id: &str) -> Result<JobState, Error> {
+        job::cancel(&self.api_base, &self.machine_id, &self.api_key, job_id).await
+    }
+
+    /// Submits `input`, waits, and deserializes the output.
+    pub async fn run<P: Serialize, O: DeserializeOwned>(&self, input: &P) -> Result<O, Error> {
+        let queued = self.submit(input).await?;
+        self.wait::<O>(&queued.id).await?.output.ok_or(Error::msg("Job completed without output."))
+    }
+}
+
+#[async_trait]
+impl<P, O> RunpodClientAPI<P, Result<CustomResult<O>, Error>>
+    for RunpodClient<Custom>
+    where P: Serialize + Send + Sync + 'static, O: DeserializeOwned + Send
+{
+    async fn request(&self, params: P) -> Result<CustomResult<O>, Error> {
+        let queued = self.submit(&params).await?;
+        self.wait(&queued.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde::Deserialize;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::client::client::{ RunpodClient, RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait };
+
+    use super::{ Custom, CustomResult };
+
+    async fn client(server: &MockServer) -> RunpodClient<Custom> {
+        Mock::given(method("POST"))
+            .and(path("/my-handler/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(server).await;
+        RunpodClientBuilder::new(Custom)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("my-handler".to_owned())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_value_and_typed_output() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Sum {
+            total: i64,
+        }
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/my-handler/status/job-1/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(
+                    json!({ "id": "job-1", "status": "COMPLETED", "executionTime": 12, "output": { "total": 6 } })
+                )
+            )
+            .mount(&server).await;
+        let client = client(&server).await;
+
+        let result: CustomResult = client.request(json!({ "numbers": [1, 2, 3] })).await.unwrap();
+        assert_eq!(result.output, Some(json!({ "total": 6 })));
+        assert_eq!(result.executionTime, Some(12));
+
+        let sum: Sum = client.run(&json!({ "numbers": [1, 2, 3] })).await.unwrap();
+        assert_eq!(sum, Sum { total: 6 });
+
+        let sent = server.received_requests().await.unwrap();
+        assert_eq!(sent[0].body_json::<Value>().unwrap(), json!({ "input": { "numbers": [1, 2, 3] } }));
+    }
+
+    #[tokio::test]
+    async fn test_failure_and_cancel() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/my-handler/status/job-1/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "FAILED", "error": "boom" }))
+            )
+            .mount(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/my-handler/cancel/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "CANCELLED" })))
+            .mount(&server).await;
+        let client = client(&server).await;
+
+        let queued = client.submit(&json!({})).await.unwrap();
+        assert_eq!(queued.status, "IN_QUEUE");
+        let error = client.wait::<Value>(&queued.id).await.unwrap_err();
+        assert!(error.to_string().contains("boom"));
+        assert_eq!(client.cancel(&queued.id).await.unwrap().status, "CANCELLED");
+    }
+}
@@ -0,0 +1,592 @@
+#![allow(non_snake_case)]
+
+use std::{ fmt, marker::PhantomData, path::Path, str::FromStr };
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::{ de::DeserializeOwned, ser::SerializeMap, Deserialize, Serialize };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{
+    backend::{ ParamFields, RunpodBackend, RunpodParams },
+    download::{ download_all, Download, DownloadOptions },
+    image::{ ImageInput, ImageOutput },
+    job::{ self, JobResult },
+};
+
+use anyhow::Error;
+
+/// A Stable Diffusion worker: where its jobs are queued and what they take and return.
+pub trait DiffusionModel: RunpodBackend + Send + Sync + 'static {
+    /// Path of the public endpoint under the API base.
+    const ENDPOINT: &'static str;
+    /// Short name used when logging requests.
+    const NAME: &'static str;
+    /// Largest `width * height` the worker renders without running out of memory.
+    const MAX_PIXELS: u64;
+    type Params: RunpodParams + ParamFields + DiffusionParamsCore + Serialize + Send + Sync + 'static;
+    type Output: DeserializeOwned + Send + 'static;
+}
+
+#[async_trait]
+pub trait DiffusionOutputFetch: Sync {
+    /// Every image in the output as the worker returned it: URLs, data URIs or base64.
+    fn images(&self) -> Vec<&str>;
+
+    /// The seed the worker used, for reproducing this output exactly. `None` when the
+    /// worker didn't report one.
+    fn seed(&self) -> Option<i64>;
+
+    /// The seed of each image, in the same order as `images`.
+    fn seeds(&self) -> Vec<Option<i64>> {
+        self.images()
+            .iter()
+            .map(|_| self.seed())
+            .collect()
+    }
+
+    /// The first image of the output.
+    async fn fetch(&self) -> Result<Vec<u8>, Error> {
+        let image = self
+            .images()
+            .first()
+            .copied()
+            .ok_or(Error::msg("Output contained no images."))?;
+        ImageOutput::parse(image)?.load().await
+    }
+
+    async fn fetch_all(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut fetched = Vec::new();
+        for image in self.images() {
+            fetched.push(ImageOutput::parse(image)?.load().await?);
+        }
+        Ok(fetched)
+    }
+
+    /// The first image of the output as a stream of chunks.
+    async fn stream(&self) -> Result<BoxStream<'static, Result<Bytes, Error>>, Error> {
+        let image = self
+            .images()
+            .first()
+            .copied()
+            .ok_or(Error::msg("Output contained no images."))?;
+        ImageOutput::parse(image)?.stream().await
+    }
+
+    /// Streams every image of the output to disk instead of holding it in memory.
+    /// Several images are numbered `name_0.png`, `name_1.png` and so on.
+    async fn download_to(&self, path: &Path, options: &DownloadOptions) -> Result<Vec<Download>, Error> {
+        let images = self
+            .images()
+            .into_iter()
+            .map(ImageOutput::parse)
+            .collect::<Result<Vec<_>, Error>>()?;
+        download_all(images, path, options).await
+    }
+}
+
+/// Workers that return one entry per image, each with its own seed.
+impl<O: DiffusionOutputFetch> DiffusionOutputFetch for Vec<O> {
+    fn images(&self) -> Vec<&str> {
+        self.iter().flat_map(|o| o.images()).collect()
+    }
+
+    /// The seed of the first image.
+    fn seed(&self) -> Option<i64> {
+        self.first().and_then(|o| o.seed())
+    }
+
+    fn seeds(&self) -> Vec<Option<i64>> {
+        self.iter().flat_map(|o| o.seeds()).collect()
+    }
+}
+
+pub type DiffusionResult<O> = JobResult<O>;
+
+/// Fields every Stable Diffusion worker accepts. Model params flatten this into
+/// their own request body.
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+pub struct DiffusionParams {
+    pub(crate) prompt: String,
+    pub(crate) negative_prompt: Option<String>,
+    pub(crate) width: Option<u64>,
+    pub(crate) height: Option<u64>,
+    #[runpod(range(min = 0.0))]
+    pub(crate) guidance_scale: Option<f64>,
+    #[runpod(range(min = 1))]
+    pub(crate) num_inference_steps: Option<u64>,
+    pub(crate) scheduler: Option<String>,
+    pub(crate) seed: Option<i64>,
+}
+
+impl DiffusionParams {
+    /// Checks the requested size before a GPU is spun up for it: both sides must be
+    /// multiples of 8 and the image at most `max_pixels`.
+    pub fn validate(&self, max_pixels: u64) -> Result<(), Error> {
+        validate_resolution(self.width, self.height, max_pixels)
+    }
+}
+
+pub(crate) fn validate_resolution(width: Option<u64>, height: Option<u64>, max_pixels: u64) -> Result<(), Error> {
+    for (name, side) in [("width", width), ("height", height)] {
+        match side {
+            Some(0) => {
+                return Err(Error::msg(std::format!("{} must be greater than 0", name)));
+            }
+            Some(side) if side % 8 != 0 => {
+                return Err(Error::msg(std::format!("{} {} is not a multiple of 8", name, side)));
+            }
+            _ => {}
+        }
+    }
+    if let (Some(width), Some(height)) = (width, height) {
+        if width.checked_mul(height).is_none_or(|pixels| pixels > max_pixels) {
+            return Err(
+                Error::msg(std::format!("Resolution {}x{} is over the {} pixel limit", width, height, max_pixels))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Access to the shared fields of a model's params.
+pub trait DiffusionParamsCore {
+    fn core(&self) -> &DiffusionParams;
+    fn core_mut(&mut self) -> &mut DiffusionParams;
+}
+
+/// Schedulers the SD v1 and v2 workers accept. `Custom` passes any other name through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StableDiffusionScheduler {
+    DDIM,
+    KEuler,
+    KEulerAncestral,
+    DPMSolverMultistep,
+    PNDM,
+    KLMS,
+    Custom(String),
+}
+
+impl StableDiffusionScheduler {
+    pub fn as_str(&self) -> &str {
+        match self {
+            StableDiffusionScheduler::DDIM => "DDIM",
+            StableDiffusionScheduler::KEuler => "K_EULER",
+            StableDiffusionScheduler::KEulerAncestral => "K_EULER_ANCESTRAL",
+            StableDiffusionScheduler::DPMSolverMultistep => "DPMSolverMultistep",
+            StableDiffusionScheduler::PNDM => "PNDM",
+            StableDiffusionScheduler::KLMS => "KLMS",
+            StableDiffusionScheduler::Custom(name) => name.as_str(),
+        }
+    }
+}
+
+impl fmt::Display for StableDiffusionScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StableDiffusionScheduler {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "DDIM" => StableDiffusionScheduler::DDIM,
+            "K_EULER" => StableDiffusionScheduler::KEuler,
+            "K_EULER_ANCESTRAL" => StableDiffusionScheduler::KEulerAncestral,
+            "DPMSolverMultistep" => StableDiffusionScheduler::DPMSolverMultistep,
+            "PNDM" => StableDiffusionScheduler::PNDM,
+            "KLMS" => StableDiffusionScheduler::KLMS,
+            other => StableDiffusionScheduler::Custom(other.to_owned()),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StableDiffusionOutput {
+    pub image: String,
+    /// Left out by workers that don't report the seed they used.
+    #[serde(default)]
+    pub seed: Option<i64>,
+}
+
+impl DiffusionOutputFetch for StableDiffusionOutput {
+    fn images(&self) -> Vec<&str> {
+        vec![self.image.as_str()]
+    }
+
+    fn seed(&self) -> Option<i64> {
+        self.seed
+    }
+}
+
+/// Default setters for the fields every Stable Diffusion worker accepts, taking the
+/// model's scheduler enum. Expanded inside each model's builder trait, so importing
+/// that trait alone brings them into scope.
+macro_rules! diffusion_setters {
+    ($scheduler:ty) => {
+        fn with_prompt(mut self, prompt: String) -> Self {
+            self.core_mut().prompt = prompt;
+            self
+        }
+
+        fn with_resolution(mut self, width: u64, height: u64) -> Self {
+            self.core_mut().width = Some(width);
+            self.core_mut().height = Some(height);
+            self
+        }
+
+        fn with_guidance_scale(mut self, cfg_scale: f64) -> Self {
+            self.core_mut().guidance_scale = Some(cfg_scale);
+            self
+        }
+
+        fn with_steps(mut self, steps: u64) -> Self {
+            self.core_mut().num_inference_steps = Some(steps);
+            self
+        }
+
+        fn with_scheduler(mut self, scheduler: $scheduler) -> Self {
+            self.core_mut().scheduler = Some(scheduler.to_string());
+            self
+        }
+
+        fn with_negative_prompt(mut self, negative_prompt: String) -> Self {
+            self.core_mut().negative_prompt = Some(negative_prompt);
+            self
+        }
+
+        fn with_seed(mut self, seed: i64) -> Self {
+            self.core_mut().seed = Some(seed);
+            self
+        }
+
+        /// Reuses the seed of a previous output to reproduce it. Fails if the output has
+        /// no seed, rather than silently rendering with a random one.
+        fn with_seed_from<O: $crate::backend::diffusion::DiffusionOutputFetch>(
+            self,
+            output: &O
+        ) -> Result<Self, anyhow::Error> {
+            let seed = output.seed().ok_or(anyhow::Error::msg("Output has no seed to reuse."))?;
+            Ok(self.with_seed(seed))
+        }
+    };
+}
+
+pub(crate) use diffusion_setters;
+
+/// Input of the SD v1 and v2 workers, which take the same fields. Each model wraps it
+/// in its own params type so requests can't be sent to the other model's endpoint.
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+pub struct StableDiffusionParams {
+    #[serde(flatten)]
+    #[runpod(skip)]
+    core: DiffusionParams,
+    num_outputs: Option<u64>,
+    #[runpod(range(min = 0.0, max = 1.0))]
+    prompt_strength: Option<f64>,
+    init_image: Option<ImageInput>,
+    mask: Option<ImageInput>,
+    lora: Option<String>,
+    lora_scale: Option<f64>,
+}
+
+impl DiffusionParamsCore for StableDiffusionParams {
+    fn core(&self) -> &DiffusionParams {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut DiffusionParams {
+        &mut self.core
+    }
+}
+
+/// Access to the shared input of the SD v1 and v2 params.
+pub trait StableDiffusionParamsCore {
+    fn params(&self) -> &StableDiffusionParams;
+    fn params_mut(&mut self) -> &mut StableDiffusionParams;
+}
+
+/// `StableDiffusionParams` for one model `M`, so a v1 request can't be sent to a v2
+/// client. `StableDiffusionV1Params` and `StableDiffusionV2Params` name it per model.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StableDiffusionModelParams<M> {
+    params: StableDiffusionParams,
+    #[serde(skip)]
+    model: PhantomData<fn() -> M>,
+}
+
+impl<M> StableDiffusionModelParams<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// Written out rather than derived, which would require `M` itself to implement them.
+impl<M> Default for StableDiffusionModelParams<M> {
+    fn default() -> Self {
+        Self { params: StableDiffusionParams::default(), model: PhantomData }
+    }
+}
+
+impl<M> Clone for StableDiffusionModelParams<M> {
+    fn clone(&self) -> Self {
+        Self { params: self.params.clone(), model: PhantomData }
+    }
+}
+
+impl<M> fmt::Debug for StableDiffusionModelParams<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.params.fmt(f)
+    }
+}
+
+impl<M> RunpodParams for StableDiffusionModelParams<M> {}
+
+impl<M> ParamFields for StableDiffusionModelParams<M> {
+    fn serialize_fields<S: SerializeMap>(&self, map: &mut S) -> Result<(), S::Error> {
+        self.params.serialize_fields(map)
+    }
+
+    fn check_ranges(&self) -> Result<(), Error> {
+        self.params.check_ranges()
+    }
+}
+
+impl<M> DiffusionParamsCore for StableDiffusionModelParams<M> {
+    fn core(&self) -> &DiffusionParams {
+        self.params.core()
+    }
+
+    fn core_mut(&mut self) -> &mut DiffusionParams {
+        self.params.core_mut()
+    }
+}
+
+impl<M> StableDiffusionParamsCore for StableDiffusionModelParams<M> {
+    fn params(&self) -> &StableDiffusionParams {
+        &self.params
+    }
+
+    fn params_mut(&mut self) -> &mut StableDiffusionParams {
+        &mut self.params
+    }
+}
+
+impl<M> StableDiffusionParamBuilderTrait for StableDiffusionModelParams<M> {}
+
+pub trait StableDiffusionParamBuilderTrait: DiffusionParamsCore + StableDiffusionParamsCore + Sized {
+    diffusion_setters!(StableDiffusionScheduler);
+
+    fn with_multiple_outputs(mut self, output_num: u64) -> Self {
+        self.params_mut().num_outputs = Some(output_num);
+        self
+    }
+
+    /// How far an init image may be changed, from 0 to 1.
+    fn with_prompt_strength(mut self, strength: f64) -> Self {
+        self.params_mut().prompt_strength = Some(strength);
+        self
+    }
+
+    fn with_lora(mut self, lora: String, scale: f64) -> Self {
+        self.params_mut().lora = Some(lora);
+        self.params_mut().lora_scale = Some(scale);
+        self
+    }
+
+    fn with_init_image(mut self, init_image: ImageInput) -> Self {
+        self.params_mut().init_image = Some(init_image);
+        self
+    }
+
+    /// Inpainting mask; white areas of the init image are repainted.
+    fn with_mask(mut self, mask: ImageInput) -> Self {
+        self.params_mut().mask = Some(mask);
+        self
+    }
+
+    fn build(self) -> Self {
+        self
+    }
+}
+
+#[async_trait]
+impl<M> RunpodClientAPI<M::Params, Result<DiffusionResult<M::Output>, Error>>
+for RunpodClient<M> where M: DiffusionModel {
+    async fn request(
+        &self,
+        params: M::Params
+    ) -> Result<DiffusionResult<M::Output>, Error> {
+        params.core().validate(M::MAX_PIXELS)?;
+        params.check_ranges()?;
+        let queued = job::submit(&self.api_base, M::ENDPOINT, &self.api_key, &params).await?;
+        job::wait(&self.api_base, M::ENDPOINT, &self.api_key, &queued.id, self.poll_time).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::json;
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::{
+            sdv2::{
+                StableDiffusionV2,
+                StableDiffusionV2Output,
+                StableDiffusionV2ParamBuilderTrait,
+                StableDiffusionV2Params,
+            },
+            sdxl::{
+                StableDiffusionXL,
+                StableDiffusionXLOutput,
+                StableDiffusionXLOutputFetch,
+                StableDiffusionXLParamBuilderTrait,
+                StableDiffusionXLParams,
+            },
+            image::{ ImageEncoding, ImageInput },
+        },
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::DiffusionResult;
+
+    const SDV2_WITHOUT_SEED: &str = include_str!("../../tests/fixtures/diffusion/sdv2_without_seed.json");
+    const SDXL_WITHOUT_SEED: &str = include_str!("../../tests/fixtures/diffusion/sdxl_without_seed.json");
+
+    #[test]
+    fn test_reproduce_from_output_seed() {
+        let output: StableDiffusionV2Output = serde_json::from_value(
+            json!({ "image": "https://example.com/1.png", "seed": 1234 })
+        ).unwrap();
+        let params = StableDiffusionV2Params::new()
+            .with_prompt("a cat".to_owned())
+            .with_negative_prompt("blurry".to_owned())
+            .with_prompt_strength(0.6)
+            .with_seed_from(&output)
+            .unwrap()
+            .build();
+
+        assert_eq!(
+            serde_json::to_value(params).unwrap(),
+            json!({ "prompt": "a cat", "negative_prompt": "blurry", "seed": 1234, "prompt_strength": 0.6 })
+        );
+
+        let empty: Vec<StableDiffusionV2Output> = Vec::new();
+        assert_eq!(empty.seed(), None);
+        assert!(StableDiffusionV2Params::new().with_seed_from(&empty).is_err());
+    }
+
+    #[test]
+    fn test_output_without_seed() {
+        let sdv2: DiffusionResult<Vec<StableDiffusionV2Output>> = serde_json::from_str(SDV2_WITHOUT_SEED).unwrap();
+        let output = sdv2.output.unwrap();
+        assert_eq!(output.seeds(), vec![None, None]);
+        let err = StableDiffusionV2Params::new().with_seed_from(&output).unwrap_err();
+        assert_eq!(err.to_string(), "Output has no seed to reuse.");
+
+        let sdxl: DiffusionResult<StableDiffusionXLOutput> = serde_json::from_str(SDXL_WITHOUT_SEED).unwrap();
+        let output = sdxl.output.unwrap();
+        assert_eq!(output.seed(), None);
+        assert!(StableDiffusionXLParams::new().with_seed_from(&output).is_err());
+    }
+
+    #[test]
+    fn test_inpainting_params() {
+        let png = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        let params = StableDiffusionV2Params::new()
+            .with_prompt("a red door".to_owned())
+            .with_init_image(ImageInput::from_url("https://example.com/house.png").unwrap())
+            .with_mask(ImageInput::from_bytes(&png, ImageEncoding::DataUri).unwrap())
+            .build();
+
+        let body = serde_json::to_value(params).unwrap();
+        assert_eq!(body["init_image"], "https://example.com/house.png");
+        assert_eq!(body["mask"], "data:image/png;base64,iVBORw0KGgo=");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_is_rejected_locally() {
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        png.resize(8 * 1024 * 1024, 0);
+        let image = ImageInput::from_bytes_with_limit(&png, ImageEncoding::Base64, usize::MAX).unwrap();
+
+        let client = RunpodClientBuilder::new(StableDiffusionXL)
+            .with_api_base(Url::parse("http://127.0.0.1:9/").unwrap())
+            .build();
+        let err = client
+            .request(StableDiffusionXLParams::new().with_prompt("a cat".to_owned()).with_init_image(image)).await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("byte limit"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_inline_and_hosted_images() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/images/2.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"hosted".to_vec()))
+            .mount(&server).await;
+
+        let output: StableDiffusionXLOutput = serde_json::from_value(json!({
+            "image_url": "data:image/png;base64,iVBORw0KGgo=",
+            "images": ["data:image/png;base64,iVBORw0KGgo=", std::format!("{}/images/2.png", server.uri())],
+            "seed": 7
+        })).unwrap();
+
+        let images = output.fetch_all().await.unwrap();
+        assert_eq!(images, vec![vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a], b"hosted".to_vec()]);
+
+        let broken: StableDiffusionXLOutput = serde_json::from_value(
+            json!({ "image_url": "oops", "images": [], "seed": 7 })
+        ).unwrap();
+        assert!(broken.fetch().await.is_err());
+    }
+
+    #[test]
+    fn test_params_flatten_core_fields() {
+        let params = StableDiffusionXLParams::new()
+            .with_prompt("a lighthouse".to_owned())
+            .with_resolution(1024, 768)
+            .with_refiner_steps(10)
+            .build();
+
+        assert_eq!(
+            serde_json::to_value(params).unwrap(),
+            json!({ "prompt": "a lighthouse", "width": 1024, "height": 768, "refiner_inference_steps": 10 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_uses_model_endpoint() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/stable-diffusion-v2/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/stable-diffusion-v2/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-1",
+                "status": "COMPLETED",
+                "output": [{ "image": "https://example.com/1.png", "seed": 42 }]
+            })))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(StableDiffusionV2)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .build();
+        let response = client
+            .request(StableDiffusionV2Params::new().with_prompt("a cat".to_owned()).with_steps(20)).await
+            .unwrap();
+
+        assert_eq!(response.output.unwrap()[0].seed, Some(42));
+    }
+}
@@ -0,0 +1,471 @@
+use std::{ path::{ Path, PathBuf }, time::Duration };
+
+use anyhow::Error;
+use bytes::Bytes;
+use futures::{ stream::{ self, BoxStream }, StreamExt, TryStreamExt };
+use log::warn;
+use reqwest::{ header::{ CONTENT_RANGE, CONTENT_TYPE, RANGE }, StatusCode, Url };
+use sha2::{ Digest, Sha256 };
+use tokio::{ fs::File, io::{ AsyncSeekExt, AsyncWriteExt } };
+
+use super::image::{ detect_mime, ImageOutput };
+
+/// How `download_to` fetches images.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Further attempts after a failed or truncated transfer.
+    pub retries: u32,
+    /// Wait before the first retry, doubled on each one after.
+    pub backoff: Duration,
+    /// Images fetched at the same time.
+    pub concurrency: usize,
+    /// Expected SHA-256 digests as hex, in image order. Images past the end aren't checked.
+    pub sha256: Vec<String>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            concurrency: 4,
+            sha256: Vec::new(),
+        }
+    }
+}
+
+impl DownloadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn with_sha256(mut self, digest: String) -> Self {
+        self.sha256.push(digest.to_lowercase());
+        self
+    }
+}
+
+/// An image written to disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Download {
+    pub path: PathBuf,
+    pub mime: Option<String>,
+    pub bytes: u64,
+    /// SHA-256 of the file as hex.
+    pub sha256: String,
+}
+
+/// State of a transfer that survives between attempts, so a retry can resume it.
+struct Partial {
+    file: File,
+    hasher: Sha256,
+    written: u64,
+    head: Vec<u8>,
+    content_type: Option<String>,
+}
+
+impl Partial {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.file.write_all(chunk).await?;
+        self.hasher.update(chunk);
+        self.written += chunk.len() as u64;
+        if self.head.len() < 16 {
+            let take = (16 - self.head.len()).min(chunk.len());
+            self.head.extend_from_slice(&chunk[..take]);
+        }
+        Ok(())
+    }
+
+    async fn restart(&mut self) -> Result<(), Error> {
+        self.file.set_len(0).await?;
+        self.file.rewind().await?;
+        self.hasher = Sha256::new();
+        self.written = 0;
+        self.head.clear();
+        Ok(())
+    }
+}
+
+/// Removes the `.part` file when dropped, unless the download finished and renamed it.
+/// This also covers downloads that are dropped mid-transfer, such as the rest of a
+/// `download_all` batch after one image fails.
+struct PartFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl ImageOutput {
+    /// The image as a stream of chunks, without buffering it.
+    pub async fn stream(&self) -> Result<BoxStream<'static, Result<Bytes, Error>>, Error> {
+        match self {
+            ImageOutput::Inline(bytes) => Ok(stream::once(futures::future::ok(Bytes::from(bytes.clone()))).boxed()),
+            ImageOutput::Url(url) => {
+                let response = reqwest::Client::new().get(url.clone()).send().await?.error_for_status()?;
+                Ok(response.bytes_stream().map_err(Error::from).boxed())
+            }
+        }
+    }
+
+    /// Streams the image to `path`, retrying failed transfers and resuming them where the
+    /// server supports ranges. If `path` has no extension one is added for the detected
+    /// type. A mismatched `sha256` removes the file and fails.
+    pub async fn download_to<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: &DownloadOptions,
+        sha256: Option<&str>
+    ) -> Result<Download, Error> {
+        let path = path.as_ref();
+        let mut part = PartFile {
+            path: path.with_file_name(
+                std::format!("{}.part", path.file_name().unwrap_or_default().to_string_lossy())
+            ),
+            keep: false,
+        };
+        let mut partial = Partial {
+            file: File::create(&part.path).await?,
+            hasher: Sha256::new(),
+            written: 0,
+            head: Vec::new(),
+            content_type: None,
+        };
+
+        let transferred = match self {
+            ImageOutput::Inline(bytes) => partial.write(bytes).await,
+            ImageOutput::Url(url) => fetch_with_retries(url, &mut partial, options).await,
+        };
+        transferred.and(partial.file.flush().await.map_err(Error::from))?;
+
+        let digest: String = partial.hasher
+            .finalize()
+            .iter()
+            .map(|b| std::format!("{:02x}", b))
+            .collect();
+        if let Some(expected) = sha256 {
+            if !expected.eq_ignore_ascii_case(&digest) {
+                return Err(Error::msg(std::format!("Checksum mismatch: expected {}, got {}", expected, digest)));
+            }
+        }
+
+        let mime = detect_mime(&partial.head)
+            .map(|m| m.to_owned())
+            .or(partial.content_type);
+        let target = match (path.extension(), mime.as_deref().and_then(extension_for)) {
+            (None, Some(extension)) => path.with_extension(extension),
+            _ => path.to_path_buf(),
+        };
+        tokio::fs::rename(&part.path, &target).await?;
+        part.keep = true;
+
+        Ok(Download {
+            path: target,
+            mime,
+            bytes: partial.written,
+            sha256: digest,
+        })
+    }
+}
+
+async fn fetch_with_retries(url: &Url, partial: &mut Partial, options: &DownloadOptions) -> Result<(), Error> {
+    let client = reqwest::Client::new();
+    let mut attempt = 0;
+    loop {
+        match fetch_once(&client, url, partial).await {
+            Ok(()) => {
+                break Ok(());
+            }
+            Err(err) if attempt < options.retries => {
+                warn!("Download of {} failed after {} bytes, retrying: {}", url, partial.written, err);
+                tokio::time::sleep(options.backoff * (1 << attempt.min(16))).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                break Err(err);
+            }
+        }
+    }
+}
+
+async fn fetch_once(client: &reqwest::Client, url: &Url, partial: &mut Partial) -> Result<(), Error> {
+    let mut request = client.get(url.clone());
+    if partial.written > 0 {
+        request = request.header(RANGE, std::format!("bytes={}-", partial.written));
+    }
+    let response = request.send().await?.error_for_status()?;
+
+    // Servers that ignore the range send the whole image again.
+    if partial.written > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+        partial.restart().await?;
+    }
+    if partial.written > 0 {
+        let start = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(range_start);
+        if start != Some(partial.written) {
+            // Appending a range that doesn't pick up where the file ends would corrupt it,
+            // so start over on the next attempt.
+            let written = partial.written;
+            partial.restart().await?;
+            return Err(Error::msg(std::format!("Resumed transfer doesn't start at byte {}", written)));
+        }
+    }
+    if partial.content_type.is_none() {
+        partial.content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .filter(|v| v.starts_with("image/"))
+            .map(|v| v.trim().to_owned());
+    }
+
+    let expected = response.content_length().map(|length| length + partial.written);
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        partial.write(&chunk?).await?;
+    }
+    match expected {
+        Some(expected) if expected != partial.written => {
+            Err(Error::msg(std::format!("Transfer ended at {} of {} bytes", partial.written, expected)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// First byte of a `Content-Range: bytes start-end/total` header.
+fn range_start(content_range: &str) -> Option<u64> {
+    content_range
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn extension_for(mime: &str) -> Option<&'static str> {
+    match mime {
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/bmp" => Some("bmp"),
+        _ => None,
+    }
+}
+
+/// Downloads several images to `path`, numbering them `name_0`, `name_1` and so on when
+/// there is more than one, at most `options.concurrency` at a time. Results keep the
+/// order of `images`.
+pub(crate) async fn download_all(
+    images: Vec<ImageOutput>,
+    path: &Path,
+    options: &DownloadOptions
+) -> Result<Vec<Download>, Error> {
+    let count = images.len();
+    stream
+        ::iter(images.into_iter().enumerate())
+        .map(|(i, image)| async move {
+            let target = if count == 1 {
+                path.to_path_buf()
+            } else {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let name = match path.extension() {
+                    Some(extension) => std::format!("{}_{}.{}", stem, i, extension.to_string_lossy()),
+                    None => std::format!("{}_{}", stem, i),
+                };
+                path.with_file_name(name)
+            };
+            image.download_to(target, options, options.sha256.get(i).map(|s| s.as_str())).await
+        })
+        .buffered(options.concurrency.max(1))
+        .try_collect().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::TryStreamExt;
+    use reqwest::Url;
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::backend::{ diffusion::DiffusionOutputFetch, sdxl::StableDiffusionXLOutput, image::ImageOutput };
+
+    use super::{ download_all, fetch_once, DownloadOptions, Partial };
+
+    const PNG: [u8; 12] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 1, 2, 3, 4];
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(std::format!("rpc-download-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_detect_type() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/out.bin"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/out.bin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(PNG.to_vec()))
+            .mount(&server).await;
+
+        let dir = scratch("retry");
+        let image = ImageOutput::Url(Url::parse(&std::format!("{}/out.bin", server.uri())).unwrap());
+        let options = DownloadOptions::new().with_backoff(Duration::ZERO);
+        let download = image.download_to(dir.join("image"), &options, None).await.unwrap();
+
+        assert_eq!(download.path, dir.join("image.png"));
+        assert_eq!(download.mime.as_deref(), Some("image/png"));
+        assert_eq!(download.bytes, 12);
+        assert_eq!(std::fs::read(&download.path).unwrap(), PNG);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        let failing = DownloadOptions::new().with_retries(0);
+        Mock::given(method("GET"))
+            .and(path("/gone.png"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server).await;
+        let gone = ImageOutput::Url(Url::parse(&std::format!("{}/gone.png", server.uri())).unwrap());
+        assert!(gone.download_to(dir.join("gone.png"), &failing, None).await.is_err());
+        assert!(!dir.join("gone.png.part").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parallel_download_with_checksums() {
+        let output: StableDiffusionXLOutput = serde_json::from_value(
+            serde_json::json!({
+                "images": ["iVBORw0KGgoBAgME", "data:image/png;base64,iVBORw0KGgoBAgME"],
+                "seed": 1
+            })
+        ).unwrap();
+        let digest = {
+            use sha2::{ Digest, Sha256 };
+            Sha256::digest(PNG)
+                .iter()
+                .map(|b| std::format!("{:02x}", b))
+                .collect::<String>()
+        };
+
+        let dir = scratch("parallel");
+        let options = DownloadOptions::new().with_sha256(digest.clone()).with_sha256(digest.clone());
+        let downloads = output.download_to(&dir.join("batch.png"), &options).await.unwrap();
+        assert_eq!(
+            downloads.iter().map(|d| d.path.clone()).collect::<Vec<_>>(),
+            vec![dir.join("batch_0.png"), dir.join("batch_1.png")]
+        );
+        assert!(downloads.iter().all(|d| d.sha256 == digest));
+
+        let wrong = DownloadOptions::new().with_sha256("00".repeat(32));
+        assert!(output.download_to(&dir.join("wrong.png"), &wrong).await.is_err());
+        assert!(!dir.join("wrong_0.png").exists());
+
+        let streamed: Vec<u8> = output
+            .stream().await
+            .unwrap()
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat().await
+            .unwrap();
+        assert_eq!(streamed, PNG);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_checks_content_range() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/resume.png"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("Content-Range", "bytes 4-11/12")
+                    .set_body_bytes(PNG[4..].to_vec())
+            )
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/shifted.png"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("Content-Range", "bytes 0-11/12")
+                    .set_body_bytes(PNG.to_vec())
+            )
+            .mount(&server).await;
+
+        let dir = scratch("resume");
+        let client = reqwest::Client::new();
+        for (name, resumed) in [("resume.png", true), ("shifted.png", false)] {
+            let mut partial = Partial {
+                file: tokio::fs::File::create(dir.join(name)).await.unwrap(),
+                hasher: sha2::Sha256::default(),
+                written: 0,
+                head: Vec::new(),
+                content_type: None,
+            };
+            partial.write(&PNG[..4]).await.unwrap();
+            let url = Url::parse(&std::format!("{}/{}", server.uri(), name)).unwrap();
+            assert_eq!(fetch_once(&client, &url, &mut partial).await.is_ok(), resumed);
+            if resumed {
+                tokio::io::AsyncWriteExt::flush(&mut partial.file).await.unwrap();
+                assert_eq!(std::fs::read(dir.join(name)).unwrap(), PNG);
+            } else {
+                assert_eq!(partial.written, 0);
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_removes_part_files() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/gone.png"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/slow.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(PNG.to_vec()).set_delay(Duration::from_secs(2)))
+            .mount(&server).await;
+
+        let dir = scratch("aborted");
+        let images = ["gone.png", "slow.png"]
+            .iter()
+            .map(|name| ImageOutput::Url(Url::parse(&std::format!("{}/{}", server.uri(), name)).unwrap()))
+            .collect();
+        let options = DownloadOptions::new().with_retries(0);
+        assert!(download_all(images, &dir.join("batch.png"), &options).await.is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+}
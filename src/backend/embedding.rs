@@ -0,0 +1,251 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ Deserialize, Serialize };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{ backend::{ RunpodBackend, RunpodParams }, image::MAX_PAYLOAD_BYTES, job::{ self, JobResult } };
+
+pub mod similarity;
+
+/// Bytes of the request body that aren't input texts: the `input` wrapper, model name
+/// and JSON punctuation, with room to spare.
+const REQUEST_OVERHEAD_BYTES: usize = 1024;
+
+pub struct Embedding;
+
+impl RunpodBackend for Embedding {}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct EmbeddingUsage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl std::ops::AddAssign for EmbeddingUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmbeddingData {
+    pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub index: usize,
+}
+
+/// Output of the worker for one batch, in the OpenAI embeddings shape.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmbeddingOutput {
+    pub data: Vec<EmbeddingData>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub usage: EmbeddingUsage,
+}
+
+pub type EmbeddingResult = JobResult<EmbeddingOutput>;
+
+/// Embeddings of every input, in input order, however many requests it took.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    pub model: Option<String>,
+    pub usage: EmbeddingUsage,
+    /// Requests the input was split into.
+    pub batches: usize,
+}
+
+impl Embeddings {
+    /// Indices and cosine similarities of the `k` vectors closest to `query`, best first.
+    pub fn top_k(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        similarity::top_k(query, &self.vectors, k)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(builder = EmbeddingParamBuilderTrait)]
+pub struct EmbeddingParams {
+    model: Option<String>,
+    input: Vec<String>,
+    /// Most inputs sent in one request. The payload limit still applies.
+    #[serde(skip)]
+    batch_size: Option<usize>,
+}
+
+impl EmbeddingParams {
+    /// Appends one text to the inputs.
+    pub fn with_text(mut self, text: String) -> Self {
+        self.input.push(text);
+        self
+    }
+}
+
+/// Splits `input` into runs of at most `batch_size` texts whose encoded size stays under
+/// `limit` bytes. Fails if a single text is over the limit on its own.
+pub(crate) fn chunk_inputs(input: &[String], batch_size: usize, limit: usize) -> Result<Vec<&[String]>, Error> {
+    let budget = limit.saturating_sub(REQUEST_OVERHEAD_BYTES);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, text) in input.iter().enumerate() {
+        // Quotes, escapes and the separating comma.
+        let encoded = serde_json::to_string(text)?.len() + 1;
+        if encoded > budget {
+            return Err(
+                Error::msg(std::format!("Input {} is {} bytes, over the {} byte limit", i, encoded, budget))
+            );
+        }
+        if i > start && (size + encoded > budget || i - start >= batch_size.max(1)) {
+            chunks.push(&input[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += encoded;
+    }
+    if start < input.len() {
+        chunks.push(&input[start..]);
+    }
+    Ok(chunks)
+}
+
+async fn request_batch(client: &RunpodClient<Embedding>, params: &EmbeddingParams) -> Result<EmbeddingOutput, Error> {
+    let queued = job::submit(&client.api_base, &client.machine_id, &client.api_key, params).await?;
+    let result = job::wait::<EmbeddingOutput>(
+        &client.api_base,
+        &client.machine_id,
+        &client.api_key,
+        &queued.id,
+        client.poll_time
+    ).await?;
+    result.output.ok_or(Error::msg("Embedding result contained no output."))
+}
+
+#[async_trait]
+impl RunpodClientAPI<EmbeddingParams, Result<Embeddings, Error>> for RunpodClient<Embedding> {
+    /// Sends the input in as many requests as the batch size and payload limit need,
+    /// one after another, and joins the results.
+    async fn request(&self, params: EmbeddingParams) -> Result<Embeddings, Error> {
+        let chunks = chunk_inputs(&params.input, params.batch_size.unwrap_or(usize::MAX), MAX_PAYLOAD_BYTES)?;
+
+        let mut embeddings = Embeddings {
+            vectors: Vec::with_capacity(params.input.len()),
+            model: None,
+            usage: EmbeddingUsage::default(),
+            batches: chunks.len(),
+        };
+        for chunk in chunks {
+            let batch = EmbeddingParams {
+                model: params.model.clone(),
+                input: chunk.to_vec(),
+                batch_size: None,
+            };
+            let mut output = request_batch(self, &batch).await?;
+            if output.data.len() != chunk.len() {
+                return Err(
+                    Error::msg(std::format!("Sent {} inputs but got {} embeddings", chunk.len(), output.data.len()))
+                );
+            }
+            output.data.sort_by_key(|d| d.index);
+            embeddings.vectors.extend(output.data.into_iter().map(|d| d.embedding));
+            embeddings.usage += output.usage;
+            embeddings.model = embeddings.model.or(output.model);
+        }
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, Request, ResponseTemplate };
+
+    use crate::client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait };
+
+    use super::{ chunk_inputs, Embedding, EmbeddingParamBuilderTrait, EmbeddingParams, EmbeddingUsage };
+
+    #[test]
+    fn test_chunk_under_payload_limit() {
+        let input: Vec<String> = (0..10).map(|i| std::format!("document {}", i)).collect();
+        // Each text encodes to 13 bytes including its comma.
+        let chunks = chunk_inputs(&input, usize::MAX, 1024 + 40).unwrap();
+        assert_eq!(
+            chunks.iter().map(|c| c.len()).collect::<Vec<_>>(),
+            vec![3, 3, 3, 1]
+        );
+        assert_eq!(chunk_inputs(&input, 4, usize::MAX).unwrap().len(), 3);
+        assert!(chunk_inputs(&["x".repeat(64)], usize::MAX, 1024 + 40).is_err());
+        assert!(chunk_inputs(&[], 4, usize::MAX).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_joined_in_order() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/embed-endpoint/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        // Every batch gets the same recorded output, listed out of order.
+        Mock::given(method("GET"))
+            .and(path("/embed-endpoint/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-1",
+                "status": "COMPLETED",
+                "output": {
+                    "object": "list",
+                    "model": "BAAI/bge-small-en-v1.5",
+                    "data": [
+                        { "object": "embedding", "index": 1, "embedding": [0.0, 1.0] },
+                        { "object": "embedding", "index": 0, "embedding": [1.0, 0.0] }
+                    ],
+                    "usage": { "prompt_tokens": 6, "total_tokens": 6 }
+                }
+            })))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(Embedding)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("embed-endpoint".to_owned())
+            .build();
+        let embeddings = client
+            .request(
+                EmbeddingParams::new()
+                    .with_model("BAAI/bge-small-en-v1.5".to_owned())
+                    .with_input(vec!["a".to_owned(), "b".to_owned(), "c".to_owned(), "d".to_owned()])
+                    .with_batch_size(2)
+            ).await
+            .unwrap();
+
+        assert_eq!(embeddings.batches, 2);
+        assert_eq!(embeddings.vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![1.0, 0.0], vec![0.0, 1.0]]);
+        assert_eq!(embeddings.usage, EmbeddingUsage { prompt_tokens: 12, total_tokens: 12 });
+        assert_eq!(embeddings.top_k(&[0.0, 2.0], 1)[0].0, 1);
+
+        let sent: Vec<Value> = server
+            .received_requests().await
+            .unwrap()
+            .iter()
+            .filter(|r| r.url.path().ends_with("/run"))
+            .map(|r: &Request| r.body_json::<Value>().unwrap())
+            .collect();
+        assert_eq!(sent[1]["input"], json!({ "model": "BAAI/bge-small-en-v1.5", "input": ["c", "d"] }));
+
+        // A batch that comes back short is an error rather than misaligned vectors.
+        let short = client.request(EmbeddingParams::new().with_input(vec!["a".to_owned()])).await;
+        assert!(short.is_err());
+
+        // Without a model the worker's default is used, so the key is left out.
+        let sent = server
+            .received_requests().await
+            .unwrap()
+            .into_iter()
+            .rfind(|r| r.url.path().ends_with("/run"))
+            .unwrap();
+        assert_eq!(sent.body_json::<Value>().unwrap()["input"], json!({ "input": ["a"] }));
+    }
+}
@@ -0,0 +1,75 @@
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| x * y)
+        .sum()
+}
+
+pub fn norm(v: &[f32]) -> f32 {
+    dot(v, v).sqrt()
+}
+
+/// Cosine similarity, or 0 when either vector is all zeros.
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 {
+        0.0
+    } else {
+        dot(a, b) / denominator
+    }
+}
+
+/// Scales `v` to unit length in place, so dot products of normalised vectors are cosines.
+pub fn normalize(v: &mut [f32]) {
+    let length = norm(v);
+    if length > 0.0 {
+        v.iter_mut().for_each(|x| {
+            *x /= length;
+        });
+    }
+}
+
+pub fn normalized(v: &[f32]) -> Vec<f32> {
+    let mut v = v.to_vec();
+    normalize(&mut v);
+    v
+}
+
+/// Indices and cosine similarities of the `k` rows of `matrix` closest to `query`,
+/// best first.
+pub fn top_k(query: &[f32], matrix: &[Vec<f32>], k: usize) -> Vec<(usize, f32)> {
+    let mut scores: Vec<(usize, f32)> = matrix
+        .iter()
+        .enumerate()
+        .map(|(i, row)| (i, cosine(query, row)))
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.truncate(k);
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ cosine, dot, normalize, normalized, top_k };
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(dot(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]), 32.0);
+        assert_eq!(cosine(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+        assert!((cosine(&[1.0, 1.0], &[2.0, 2.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+
+        let mut v = vec![3.0, 4.0];
+        normalize(&mut v);
+        assert_eq!(v, vec![0.6, 0.8]);
+        assert_eq!(normalized(&[0.0, 0.0]), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_top_k() {
+        let matrix = vec![vec![1.0, 0.0], vec![0.7, 0.7], vec![0.0, 1.0], vec![-1.0, 0.0]];
+        let best = top_k(&[1.0, 0.2], &matrix, 2);
+        assert_eq!(best.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(top_k(&[1.0, 0.0], &matrix, 10).len(), 4);
+    }
+}
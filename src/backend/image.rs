@@ -0,0 +1,173 @@
+use std::{ fs, path::Path };
+
+use anyhow::Error;
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use reqwest::Url;
+use serde::{ Deserialize, Serialize };
+
+/// Largest request body RunPod accepts on the async `/run` route.
+pub const MAX_PAYLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageEncoding {
+    /// `data:<mime>;base64,<data>`
+    DataUri,
+    /// Bare base64 without a mime prefix.
+    Base64,
+}
+
+/// An input image for a worker: an http(s) URL, a data URI or raw base64.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct ImageInput(String);
+
+impl ImageInput {
+    pub fn from_url(url: &str) -> Result<Self, Error> {
+        let parsed = Url::parse(url)?;
+        match parsed.scheme() {
+            "http" | "https" => Ok(ImageInput(url.to_owned())),
+            scheme => Err(Error::msg(std::format!("Unsupported image URL scheme '{}'", scheme))),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], encoding: ImageEncoding) -> Result<Self, Error> {
+        Self::from_bytes_with_limit(bytes, encoding, MAX_PAYLOAD_BYTES)
+    }
+
+    /// Encodes `bytes`, failing if the encoded image would be larger than `limit`.
+    pub fn from_bytes_with_limit(bytes: &[u8], encoding: ImageEncoding, limit: usize) -> Result<Self, Error> {
+        let mime = detect_mime(bytes).ok_or(Error::msg("Unrecognised image format"))?;
+        let encoded = match encoding {
+            ImageEncoding::Base64 => STANDARD.encode(bytes),
+            ImageEncoding::DataUri => std::format!("data:{};base64,{}", mime, STANDARD.encode(bytes)),
+        };
+        if encoded.len() > limit {
+            return Err(
+                Error::msg(
+                    std::format!("Encoded image is {} bytes, over the {} byte limit", encoded.len(), limit)
+                )
+            );
+        }
+        Ok(ImageInput(encoded))
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P, encoding: ImageEncoding) -> Result<Self, Error> {
+        Self::from_bytes(&fs::read(path)?, encoding)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An image as a worker returned it: hosted at a URL or inline in the response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageOutput {
+    Url(Url),
+    Inline(Vec<u8>),
+}
+
+impl ImageOutput {
+    /// Recognises http(s) URLs, `data:` URIs and raw base64. Anything else is an error
+    /// rather than something to download.
+    pub fn parse(image: &str) -> Result<Self, Error> {
+        let image = image.trim();
+        if let Some(data) = image.strip_prefix("data:") {
+            let (header, payload) = data
+                .split_once(',')
+                .ok_or(Error::msg("Malformed data URI in image output"))?;
+            if !header.ends_with(";base64") {
+                return Err(Error::msg("Only base64 data URIs are supported in image output"));
+            }
+            return Ok(ImageOutput::Inline(STANDARD.decode(payload)?));
+        }
+        if let Ok(url) = Url::parse(image) {
+            if matches!(url.scheme(), "http" | "https") {
+                return Ok(ImageOutput::Url(url));
+            }
+        }
+        match STANDARD.decode(image) {
+            Ok(bytes) if detect_mime(&bytes).is_some() => Ok(ImageOutput::Inline(bytes)),
+            _ => {
+                let preview: String = image.chars().take(32).collect();
+                Err(Error::msg(std::format!("Unrecognised image output '{}'", preview)))
+            }
+        }
+    }
+
+    pub async fn load(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            ImageOutput::Inline(bytes) => Ok(bytes.clone()),
+            ImageOutput::Url(url) => {
+                reqwest::Client
+                    ::new()
+                    .get(url.clone())
+                    .send().await?
+                    .error_for_status()?
+                    .bytes().await
+                    .map(|x| x.to_vec())
+                    .map_err(|x| x.into())
+            }
+        }
+    }
+}
+
+/// Mime type of an encoded image, sniffed from its magic bytes.
+pub fn detect_mime(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::{ detect_mime, ImageEncoding, ImageInput, ImageOutput };
+
+    const PNG_HEADER: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(detect_mime(&PNG_HEADER), Some("image/png"));
+        assert_eq!(
+            ImageInput::from_bytes(&PNG_HEADER, ImageEncoding::DataUri).unwrap().as_str(),
+            "data:image/png;base64,iVBORw0KGgo="
+        );
+        assert_eq!(ImageInput::from_bytes(&PNG_HEADER, ImageEncoding::Base64).unwrap().as_str(), "iVBORw0KGgo=");
+        assert!(ImageInput::from_bytes(b"not an image", ImageEncoding::Base64).is_err());
+    }
+
+    #[test]
+    fn test_size_limit() {
+        assert!(ImageInput::from_bytes_with_limit(&PNG_HEADER, ImageEncoding::Base64, 8).is_err());
+        assert!(ImageInput::from_bytes_with_limit(&PNG_HEADER, ImageEncoding::Base64, 12).is_ok());
+    }
+
+    #[test]
+    fn test_urls() {
+        assert!(ImageInput::from_url("https://example.com/cat.png").is_ok());
+        assert!(ImageInput::from_url("file:///tmp/cat.png").is_err());
+    }
+
+    #[test]
+    fn test_parse_outputs() {
+        assert_eq!(
+            ImageOutput::parse("data:image/png;base64,iVBORw0KGgo=").unwrap(),
+            ImageOutput::Inline(PNG_HEADER.to_vec())
+        );
+        assert_eq!(ImageOutput::parse("iVBORw0KGgo=").unwrap(), ImageOutput::Inline(PNG_HEADER.to_vec()));
+        assert_eq!(
+            ImageOutput::parse("https://example.com/out.png").unwrap(),
+            ImageOutput::Url(Url::parse("https://example.com/out.png").unwrap())
+        );
+        assert!(ImageOutput::parse("").is_err());
+        assert!(ImageOutput::parse("not an image").is_err());
+        assert!(ImageOutput::parse("aGVsbG8=").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+#![allow(non_snake_case)]
+
+use std::time::Duration;
+
+use anyhow::Error;
+use log::info;
+use reqwest::Url;
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use serde_json::{ json, Value };
+
+use super::image::MAX_PAYLOAD_BYTES;
+
+/// A job as the queue reports it right after submitting or cancelling.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JobState {
+    pub id: String,
+    pub status: String,
+}
+
+/// The status route's view of a job, with the worker's output once it has finished.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobResult<O> {
+    pub delayTime: Option<u64>,
+    pub executionTime: Option<u64>,
+    pub id: Option<String>,
+    pub output: Option<O>,
+    pub status: Option<String>,
+    pub error: Option<String>,
+}
+
+impl<O> JobResult<O> {
+    /// Whether the job has stopped, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status.as_deref(), Some("COMPLETED" | "FAILED" | "CANCELLED" | "TIMED_OUT"))
+    }
+}
+
+fn job_url(api_base: &Url, endpoint: &str, route: &str, job_id: &str) -> Result<Url, Error> {
+    Ok(
+        api_base
+            .join(std::format!("{}/", endpoint).as_str())?
+            .join(std::format!("{}/", route).as_str())?
+            .join(std::format!("{}/", job_id).as_str())?
+    )
+}
+
+/// Queues `input` on `endpoint`, failing if the request is over the payload limit or
+/// the queue doesn't hand back a job id.
+pub(crate) async fn submit<P: Serialize>(
+    api_base: &Url,
+    endpoint: &str,
+    api_key: &str,
+    input: &P
+) -> Result<JobState, Error> {
+    let run: Url = api_base.join(std::format!("{}/", endpoint).as_str())?.join("run")?;
+
+    let request = json!({
+        "input": input
+    });
+    let size = serde_json::to_vec(&request)?.len();
+    if size > MAX_PAYLOAD_BYTES {
+        return Err(
+            Error::msg(std::format!("Request is {} bytes, over the {} byte limit", size, MAX_PAYLOAD_BYTES))
+        );
+    }
+
+    info!("Job Request: {} bytes to {}", size, endpoint);
+
+    let response = reqwest::Client
+        ::new()
+        .post(run)
+        .bearer_auth(api_key)
+        .json(&request)
+        .send().await?
+        .json::<Value>().await?;
+
+    info!("Job Result: {:#?}", response);
+
+    serde_json::from_value(response).map_err(|_| Error::msg("Couldn't queue job!"))
+}
+
+pub(crate) async fn status<O: DeserializeOwned>(
+    api_base: &Url,
+    endpoint: &str,
+    api_key: &str,
+    job_id: &str
+) -> Result<JobResult<O>, Error> {
+    reqwest::Client
+        ::new()
+        .get(job_url(api_base, endpoint, "status", job_id)?)
+        .bearer_auth(api_key)
+        .send().await?
+        .json::<JobResult<O>>().await
+        .map_err(|x| x.into())
+}
+
+/// Polls the job every `poll_time` until it completes. Failed, cancelled and timed out
+/// jobs are errors.
+pub(crate) async fn wait<O: DeserializeOwned>(
+    api_base: &Url,
+    endpoint: &str,
+    api_key: &str,
+    job_id: &str,
+    poll_time: Duration
+) -> Result<JobResult<O>, Error> {
+    loop {
+        let response = status::<O>(api_base, endpoint, api_key, job_id).await?;
+
+        match response.status.as_deref().ok_or(Error::msg("Didn't get status from job queue."))? {
+            "COMPLETED" => {
+                // All done
+                break Ok(response);
+            }
+            "FAILED" => {
+                break Err(
+                    Error::msg(std::format!("RunPod job status FAILED: {}", response.error.unwrap_or_default()))
+                );
+            }
+            status @ ("CANCELLED" | "TIMED_OUT") => {
+                break Err(Error::msg(std::format!("RunPod job status {}.", status)));
+            }
+            _ => {
+                tokio::time::sleep(poll_time).await;
+            }
+        }
+    }
+}
+
+pub(crate) async fn cancel(api_base: &Url, endpoint: &str, api_key: &str, job_id: &str) -> Result<JobState, Error> {
+    reqwest::Client
+        ::new()
+        .post(job_url(api_base, endpoint, "cancel", job_id)?)
+        .bearer_auth(api_key)
+        .send().await?
+        .error_for_status()?
+        .json::<JobState>().await
+        .map_err(|x| x.into())
+}
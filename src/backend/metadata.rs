@@ -0,0 +1,560 @@
+use std::{ fs, path::{ Path, PathBuf } };
+
+use anyhow::Error;
+use image::ImageFormat;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+
+use super::diffusion::{ DiffusionOutputFetch, DiffusionParamsCore, DiffusionResult };
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Text chunk key A1111 and most image viewers read generation settings from.
+pub const PARAMETERS_KEY: &str = "parameters";
+
+/// The settings an image was generated with, in the shape A1111 writes into PNGs.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct GenerationMetadata {
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub steps: Option<u64>,
+    pub sampler: Option<String>,
+    pub cfg_scale: Option<f64>,
+    pub seed: Option<i64>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub model: Option<String>,
+}
+
+impl GenerationMetadata {
+    pub fn from_params<P: DiffusionParamsCore>(params: &P) -> Self {
+        let core = params.core();
+        Self {
+            prompt: core.prompt.clone(),
+            negative_prompt: core.negative_prompt.clone(),
+            steps: core.num_inference_steps,
+            sampler: core.scheduler.clone(),
+            cfg_scale: core.guidance_scale,
+            seed: core.seed,
+            width: core.width,
+            height: core.height,
+            model: None,
+        }
+    }
+
+    /// Writes these settings onto `params`, for re-running a saved generation.
+    pub fn apply_to<P: DiffusionParamsCore>(&self, mut params: P) -> P {
+        let core = params.core_mut();
+        core.prompt = self.prompt.clone();
+        core.negative_prompt = self.negative_prompt.clone();
+        core.num_inference_steps = self.steps;
+        core.scheduler = self.sampler.clone();
+        core.guidance_scale = self.cfg_scale;
+        core.seed = self.seed;
+        core.width = self.width;
+        core.height = self.height;
+        params
+    }
+
+    /// Formats the A1111 "parameters" text: the prompt, an optional negative prompt
+    /// line and a comma separated settings line.
+    pub fn to_a1111(&self) -> String {
+        let mut settings: Vec<String> = Vec::new();
+        if let Some(steps) = self.steps {
+            settings.push(std::format!("Steps: {}", steps));
+        }
+        if let Some(sampler) = self.sampler.as_ref() {
+            settings.push(std::format!("Sampler: {}", sampler));
+        }
+        if let Some(cfg_scale) = self.cfg_scale {
+            settings.push(std::format!("CFG scale: {}", cfg_scale));
+        }
+        if let Some(seed) = self.seed {
+            settings.push(std::format!("Seed: {}", seed));
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            settings.push(std::format!("Size: {}x{}", width, height));
+        }
+        if let Some(model) = self.model.as_ref() {
+            settings.push(std::format!("Model: {}", model));
+        }
+
+        let mut text = self.prompt.clone();
+        if let Some(negative_prompt) = self.negative_prompt.as_ref() {
+            text.push_str(&std::format!("\nNegative prompt: {}", negative_prompt));
+        }
+        if !settings.is_empty() {
+            text.push('\n');
+            text.push_str(&settings.join(", "));
+        }
+        text
+    }
+
+    pub fn parse_a1111(text: &str) -> Result<Self, Error> {
+        let mut lines: Vec<&str> = text.lines().collect();
+        let mut metadata = GenerationMetadata::default();
+
+        // The prompt always comes first, so a single line is never the settings.
+        if lines.len() > 1 && lines.last().is_some_and(|line| is_settings_line(line)) {
+            let settings = lines.pop().unwrap();
+            for field in split_settings(settings) {
+                let (key, value) = match field.split_once(": ") {
+                    Some(kv) => kv,
+                    None => {
+                        continue;
+                    }
+                };
+                let value = value.trim_matches('"');
+                match key {
+                    "Steps" => {
+                        metadata.steps = Some(value.parse()?);
+                    }
+                    "Sampler" => {
+                        metadata.sampler = Some(value.to_owned());
+                    }
+                    "CFG scale" => {
+                        metadata.cfg_scale = Some(value.parse()?);
+                    }
+                    "Seed" => {
+                        metadata.seed = Some(value.parse()?);
+                    }
+                    "Size" => {
+                        let (width, height) = value
+                            .split_once('x')
+                            .ok_or(Error::msg(std::format!("Invalid size '{}'", value)))?;
+                        metadata.width = Some(width.parse()?);
+                        metadata.height = Some(height.parse()?);
+                    }
+                    "Model" => {
+                        metadata.model = Some(value.to_owned());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(i) = lines.iter().position(|line| line.starts_with("Negative prompt: ")) {
+            let negative: Vec<&str> = lines.split_off(i);
+            metadata.negative_prompt = Some(negative.join("\n")["Negative prompt: ".len()..].to_owned());
+        }
+        metadata.prompt = lines.join("\n");
+        Ok(metadata)
+    }
+
+    /// Reads the A1111 parameters embedded in a saved PNG.
+    pub fn read_png<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let png = fs::read(path)?;
+        let text = read_png_text(&png)?
+            .into_iter()
+            .find(|(key, _)| key == PARAMETERS_KEY)
+            .map(|(_, text)| text)
+            .ok_or(Error::msg("PNG has no generation parameters"))?;
+        Self::parse_a1111(&text)
+    }
+}
+
+/// Keys `to_a1111` writes on the settings line.
+const SETTINGS_KEYS: [&str; 6] = ["Steps", "Sampler", "CFG scale", "Seed", "Size", "Model"];
+
+// A1111 starts the settings line with Steps, but `to_a1111` leaves out whatever is
+// unset, so the line is recognised by its `Key: value, ...` shape and a known key.
+fn is_settings_line(line: &str) -> bool {
+    let keys: Vec<Option<&str>> = split_settings(line)
+        .into_iter()
+        .map(|field| field.split_once(": ").map(|(key, _)| key))
+        .collect();
+    keys.iter().all(|key| key.is_some_and(|key| !key.is_empty())) &&
+        keys.iter().any(|key| key.is_some_and(|key| SETTINGS_KEYS.contains(&key)))
+}
+
+// Settings are comma separated, but quoted values may contain commas themselves.
+fn split_settings(settings: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in settings.char_indices() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+            }
+            ',' if !quoted => {
+                fields.push(settings[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(settings[start..].trim());
+    fields
+}
+
+/// Where and how `save_to` writes generated images.
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    /// Also write a `.json` file next to each image with the params and job details.
+    pub sidecar: bool,
+    /// Recorded as the model in the embedded parameters.
+    pub endpoint: Option<String>,
+}
+
+impl<O: DiffusionOutputFetch> DiffusionResult<O> {
+    /// Fetches every image of the result and writes it to `path` with the generation
+    /// parameters embedded. Results with several images are numbered `name_0.png`,
+    /// `name_1.png` and so on. Returns the paths written.
+    ///
+    /// Only PNGs carry the parameters. Other formats, such as JPEG, are written
+    /// unchanged with their own extension; use `SaveOptions::sidecar` to keep their
+    /// parameters.
+    pub async fn save_to<Q, P>(&self, path: Q, params: &P, options: &SaveOptions) -> Result<Vec<PathBuf>, Error>
+        where Q: AsRef<Path>, P: DiffusionParamsCore + Serialize
+    {
+        let output = self.output.as_ref().ok_or(Error::msg("Result contained no output."))?;
+        let images = output.fetch_all().await?;
+
+        let seeds = output.seeds();
+
+        let mut metadata = GenerationMetadata::from_params(params);
+        metadata.model = options.endpoint.clone();
+
+        let path = path.as_ref();
+        let mut written = Vec::new();
+        for (i, image) in images.iter().enumerate() {
+            metadata.seed = seeds.get(i).copied().flatten().or(params.core().seed);
+
+            let format = image::guess_format(image).ok();
+            let extensions = format.map(|f| f.extensions_str()).unwrap_or(&["png"]);
+            let target = if images.len() == 1 {
+                let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+                if extension.is_some_and(|e| extensions.contains(&e.as_str())) {
+                    path.to_path_buf()
+                } else {
+                    path.with_extension(extensions[0])
+                }
+            } else {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                path.with_file_name(std::format!("{}_{}.{}", stem, i, extensions[0]))
+            };
+
+            if format == Some(ImageFormat::Png) {
+                fs::write(&target, embed_png_text(image, PARAMETERS_KEY, &metadata.to_a1111())?)?;
+            } else {
+                fs::write(&target, image)?;
+            }
+            if options.sidecar {
+                let sidecar = json!({
+                    "id": self.id,
+                    "delayTime": self.delayTime,
+                    "executionTime": self.executionTime,
+                    "endpoint": options.endpoint,
+                    "seed": metadata.seed,
+                    "params": params,
+                    "parameters": metadata,
+                });
+                fs::write(target.with_extension("json"), serde_json::to_vec_pretty(&sidecar)?)?;
+            }
+            written.push(target);
+        }
+        Ok(written)
+    }
+}
+
+/// Loads the params a PNG written by `save_to` was generated with.
+pub fn read_params<P, Q>(path: Q) -> Result<P, Error>
+    where P: DiffusionParamsCore + Default, Q: AsRef<Path>
+{
+    Ok(GenerationMetadata::read_png(path)?.apply_to(P::default()))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Offset, type and data of a PNG chunk.
+type PngChunk<'a> = (usize, [u8; 4], &'a [u8]);
+
+fn png_chunks(png: &[u8]) -> Result<Vec<PngChunk<'_>>, Error> {
+    if !png.starts_with(&PNG_SIGNATURE) {
+        return Err(Error::msg("Image is not a PNG"));
+    }
+    let mut chunks = Vec::new();
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 12 <= png.len() {
+        let length = u32::from_be_bytes(png[offset..offset + 4].try_into()?) as usize;
+        let kind: [u8; 4] = png[offset + 4..offset + 8].try_into()?;
+        let end = offset + 12 + length;
+        if end > png.len() {
+            return Err(Error::msg("Truncated PNG chunk"));
+        }
+        chunks.push((offset, kind, &png[offset + 8..offset + 8 + length]));
+        offset = end;
+    }
+    Ok(chunks)
+}
+
+/// Returns `png` with a text chunk holding `text` under `key`, replacing any existing
+/// one. Uses `tEXt` for Latin-1 text and uncompressed `iTXt` otherwise.
+pub fn embed_png_text(png: &[u8], key: &str, text: &str) -> Result<Vec<u8>, Error> {
+    let chunks = png_chunks(png)?;
+    let (ihdr_offset, kind, ihdr) = chunks.first().ok_or(Error::msg("Empty PNG"))?;
+    if kind != b"IHDR" {
+        return Err(Error::msg("PNG does not start with IHDR"));
+    }
+
+    let mut data: Vec<u8> = Vec::new();
+    let kind: &[u8; 4] = if text.chars().all(|c| (c as u32) < 256) {
+        data.extend(key.bytes());
+        data.push(0);
+        data.extend(text.chars().map(|c| c as u8));
+        b"tEXt"
+    } else {
+        data.extend(key.bytes());
+        data.extend([0, 0, 0, 0, 0]);
+        data.extend(text.as_bytes());
+        b"iTXt"
+    };
+
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend((data.len() as u32).to_be_bytes());
+    chunk.extend(kind);
+    chunk.extend(&data);
+    chunk.extend(crc32(&chunk[4..]).to_be_bytes());
+
+    let mut result = png[..ihdr_offset + 12 + ihdr.len()].to_vec();
+    result.extend(chunk);
+    for (i, (offset, kind, data)) in chunks.iter().enumerate().skip(1) {
+        let end = chunks.get(i + 1).map(|c| c.0).unwrap_or(png.len());
+        let replaced = (kind == b"tEXt" || kind == b"iTXt") &&
+            data.split(|b| *b == 0).next() == Some(key.as_bytes());
+        if !replaced {
+            result.extend(&png[*offset..end]);
+        }
+    }
+    Ok(result)
+}
+
+/// Every `tEXt` and uncompressed `iTXt` entry of `png` as key/text pairs.
+pub fn read_png_text(png: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let mut entries = Vec::new();
+    for (_, kind, data) in png_chunks(png)? {
+        let (key, rest) = match data.iter().position(|b| *b == 0) {
+            Some(i) => (&data[..i], &data[i + 1..]),
+            None => {
+                continue;
+            }
+        };
+        let key: String = key.iter().map(|b| *b as char).collect();
+        match &kind {
+            b"tEXt" => entries.push((key, rest.iter().map(|b| *b as char).collect())),
+            // compression flag, method, then NUL terminated language and translated key
+            b"iTXt" if rest.first() == Some(&0) => {
+                // A chunk cut off before the method byte has no text to read.
+                let text = match rest.get(2..) {
+                    Some(rest) => rest.splitn(3, |b| *b == 0).nth(2).unwrap_or_default(),
+                    None => {
+                        continue;
+                    }
+                };
+                entries.push((key, String::from_utf8_lossy(text).into_owned()));
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageFormat;
+    use serde_json::{ json, Value };
+
+    use crate::backend::{
+        diffusion::{ DiffusionOutputFetch, DiffusionResult, StableDiffusionOutput },
+        sdv1::{ StableDiffusionV1ParamBuilderTrait, StableDiffusionV1Params },
+        sdxl::{ StableDiffusionXLOutput, StableDiffusionXLParamBuilderTrait, StableDiffusionXLParams },
+    };
+
+    use super::{ embed_png_text, read_params, read_png_text, GenerationMetadata, SaveOptions };
+
+    // 1x1 transparent PNG
+    const PIXEL: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR4nGNgYGBgAAAABQABpfZFQAAAAABJRU5ErkJggg==";
+
+    #[test]
+    fn test_a1111_round_trip() {
+        let metadata = GenerationMetadata {
+            prompt: "a castle,\nat dusk".to_owned(),
+            negative_prompt: Some("blurry".to_owned()),
+            steps: Some(30),
+            sampler: Some("DDIM".to_owned()),
+            cfg_scale: Some(7.5),
+            seed: Some(42),
+            width: Some(1024),
+            height: Some(768),
+            model: Some("sdxl".to_owned()),
+        };
+        let text = metadata.to_a1111();
+        assert_eq!(
+            text,
+            "a castle,\nat dusk\nNegative prompt: blurry\nSteps: 30, Sampler: DDIM, CFG scale: 7.5, Seed: 42, Size: 1024x768, Model: sdxl"
+        );
+        assert_eq!(GenerationMetadata::parse_a1111(&text).unwrap(), metadata);
+    }
+
+    #[test]
+    fn test_a1111_round_trip_without_steps() {
+        let metadata = GenerationMetadata { prompt: "a cat".to_owned(), seed: Some(99), ..Default::default() };
+        let text = metadata.to_a1111();
+        assert_eq!(text, "a cat\nSeed: 99");
+        assert_eq!(GenerationMetadata::parse_a1111(&text).unwrap(), metadata);
+
+        // Prompts that merely contain a colon aren't mistaken for settings.
+        let prompt = GenerationMetadata::parse_a1111("a cat\nstyle: watercolor").unwrap();
+        assert_eq!(prompt.prompt, "a cat\nstyle: watercolor");
+        assert_eq!(GenerationMetadata::parse_a1111("Seed: 5").unwrap().prompt, "Seed: 5");
+    }
+
+    #[test]
+    fn test_embed_and_read_text_chunks() {
+        use base64::{ engine::general_purpose::STANDARD, Engine };
+        let png = STANDARD.decode(PIXEL).unwrap();
+
+        let tagged = embed_png_text(&png, "parameters", "first").unwrap();
+        let tagged = embed_png_text(&tagged, "parameters", "zweite Größe ✓").unwrap();
+        assert_eq!(read_png_text(&tagged).unwrap(), vec![("parameters".to_owned(), "zweite Größe ✓".to_owned())]);
+        assert!(embed_png_text(b"GIF89a", "parameters", "x").is_err());
+
+        // An iTXt chunk that ends right after its compression flag.
+        let mut truncated = png[..33].to_vec();
+        truncated.extend(12u32.to_be_bytes());
+        truncated.extend(b"iTXtparameters\0\0");
+        truncated.extend([0; 4]);
+        truncated.extend(&png[33..]);
+        assert!(read_png_text(&truncated).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_save_and_reload_params() {
+        let dir = std::env::temp_dir().join(std::format!("rpc-metadata-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let params = StableDiffusionXLParams::new()
+            .with_prompt("a lighthouse".to_owned())
+            .with_steps(25)
+            .with_resolution(1024, 1024)
+            .with_refiner_steps(5);
+        let result = DiffusionResult {
+            delayTime: Some(120),
+            executionTime: Some(4000),
+            id: Some("job-9".to_owned()),
+            output: Some(StableDiffusionXLOutput {
+                image_url: std::format!("data:image/png;base64,{}", PIXEL),
+                images: Vec::new(),
+                seed: Some(99),
+            }),
+            status: Some("COMPLETED".to_owned()),
+            error: None,
+        };
+
+        let options = SaveOptions { sidecar: true, endpoint: Some("sdxl".to_owned()) };
+        let written = result.save_to(dir.join("lighthouse.png"), &params, &options).await.unwrap();
+        assert_eq!(written, vec![dir.join("lighthouse.png")]);
+
+        let sidecar: Value = serde_json::from_slice(&std::fs::read(dir.join("lighthouse.json")).unwrap()).unwrap();
+        assert_eq!(sidecar["id"], "job-9");
+        assert_eq!(sidecar["executionTime"], 4000);
+        assert_eq!(sidecar["params"]["refiner_inference_steps"], 5);
+
+        let reloaded: StableDiffusionXLParams = read_params(dir.join("lighthouse.png")).unwrap();
+        assert_eq!(
+            serde_json::to_value(reloaded).unwrap(),
+            json!({ "prompt": "a lighthouse", "num_inference_steps": 25, "width": 1024, "height": 1024, "seed": 99 })
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_batch_with_seed_per_image() {
+        let dir = std::env::temp_dir().join(std::format!("rpc-metadata-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let params = StableDiffusionV1Params::new()
+            .with_prompt("a fox".to_owned())
+            .with_multiple_outputs(2);
+        let image = |seed| StableDiffusionOutput { image: std::format!("data:image/png;base64,{}", PIXEL), seed: Some(seed) };
+        let result = DiffusionResult {
+            delayTime: None,
+            executionTime: None,
+            id: Some("job-3".to_owned()),
+            output: Some(vec![image(7), image(8)]),
+            status: Some("COMPLETED".to_owned()),
+            error: None,
+        };
+
+        let written = result.save_to(dir.join("fox.png"), &params, &SaveOptions::default()).await.unwrap();
+        let seeds: Vec<Option<i64>> = written
+            .iter()
+            .map(|path| GenerationMetadata::read_png(path).unwrap().seed)
+            .collect();
+        assert_eq!(seeds, vec![Some(7), Some(8)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// An output whose worker doesn't report seeds.
+    struct Unseeded(Vec<String>);
+
+    impl DiffusionOutputFetch for Unseeded {
+        fn images(&self) -> Vec<&str> {
+            self.0
+                .iter()
+                .map(|i| i.as_str())
+                .collect()
+        }
+
+        fn seed(&self) -> Option<i64> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_jpeg_unchanged_and_fall_back_to_params_seed() {
+        use base64::{ engine::general_purpose::STANDARD, Engine };
+
+        let dir = std::env::temp_dir().join(std::format!("rpc-metadata-jpeg-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut jpeg = Vec::new();
+        image::RgbImage::new(1, 1).write_to(&mut std::io::Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+        let result = DiffusionResult {
+            delayTime: None,
+            executionTime: None,
+            id: None,
+            output: Some(
+                Unseeded(
+                    vec![
+                        std::format!("data:image/png;base64,{}", PIXEL),
+                        std::format!("data:image/jpeg;base64,{}", STANDARD.encode(&jpeg))
+                    ]
+                )
+            ),
+            status: Some("COMPLETED".to_owned()),
+            error: None,
+        };
+        let params = StableDiffusionV1Params::new().with_prompt("a fox".to_owned()).with_seed(5);
+
+        let written = result.save_to(dir.join("fox.png"), &params, &SaveOptions::default()).await.unwrap();
+        assert_eq!(written, vec![dir.join("fox_0.png"), dir.join("fox_1.jpg")]);
+        assert_eq!(GenerationMetadata::read_png(&written[0]).unwrap().seed, Some(5));
+        assert_eq!(std::fs::read(&written[1]).unwrap(), jpeg);
+
+        let single = DiffusionResult { output: Some(Unseeded(vec![result.output.unwrap().0.remove(1)])), ..result };
+        let written = single.save_to(dir.join("one.png"), &params, &SaveOptions::default()).await.unwrap();
+        assert_eq!(written, vec![dir.join("one.jpg")]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
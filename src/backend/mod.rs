@@ -1,5 +1,20 @@
 pub mod vllm;
+#[allow(clippy::module_inception)] // `backend::backend` is part of the public path
 pub mod backend;
+pub mod a1111;
+pub mod audio;
+pub mod comfyui;
+pub mod custom;
+pub mod diffusion;
+pub mod download;
+pub mod embedding;
+pub mod image;
+pub mod job;
+pub mod metadata;
+pub mod schema;
 pub mod sdv1;
 pub mod sdv2;
-pub mod sdxl;
\ No newline at end of file
+pub mod sdxl;
+pub mod sweep;
+pub mod tts;
+pub mod whisper;
\ No newline at end of file
@@ -0,0 +1,390 @@
+use std::{ collections::BTreeMap, fs, path::Path };
+
+use anyhow::Error;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ Map, Value };
+
+use crate::client::client::RunpodClient;
+
+use super::{ custom::{ Custom, CustomResult }, job };
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl SchemaType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            SchemaType::String => value.is_string(),
+            SchemaType::Integer => value.is_i64() || value.is_u64(),
+            SchemaType::Number => value.is_number(),
+            SchemaType::Boolean => value.is_boolean(),
+            SchemaType::Array => value.is_array(),
+            SchemaType::Object => value.is_object(),
+        }
+    }
+}
+
+/// The subset of JSON Schema used to describe worker inputs and outputs. Keywords
+/// outside it are rejected when the schema is loaded rather than silently ignored.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Schema {
+    #[serde(rename = "type")]
+    pub kind: SchemaType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclusive_minimum: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclusive_maximum: Option<f64>,
+    /// Bounds on the length of strings, in characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+    /// Bounds on the number of items in arrays.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_items: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
+    #[serde(default, rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub choices: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<Schema>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, Schema>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    /// Whether an object may carry properties that aren't listed.
+    #[serde(default = "default_additional_properties")]
+    pub additional_properties: bool,
+}
+
+fn default_additional_properties() -> bool {
+    true
+}
+
+impl Schema {
+    /// Fills in defaults for missing properties, recursing into nested objects.
+    pub fn apply_defaults(&self, value: &mut Value) {
+        if let Value::Object(fields) = value {
+            for (name, property) in &self.properties {
+                match fields.get_mut(name) {
+                    Some(field) => property.apply_defaults(field),
+                    None => {
+                        if let Some(default) = property.default.clone() {
+                            fields.insert(name.clone(), default);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every problem with `value`, each prefixed with its path from `path`.
+    pub fn errors(&self, value: &Value, path: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.collect_errors(value, path, &mut errors);
+        errors
+    }
+
+    pub fn validate(&self, value: &Value, path: &str) -> Result<(), Error> {
+        let errors = self.errors(value, path);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::msg(errors.join("; ")))
+        }
+    }
+
+    fn collect_errors(&self, value: &Value, path: &str, errors: &mut Vec<String>) {
+        if !self.kind.matches(value) {
+            errors.push(std::format!("{}: expected {:?}, got {}", path, self.kind, value));
+            return;
+        }
+        if let Some(choices) = &self.choices {
+            if !choices.contains(value) {
+                errors.push(std::format!("{}: {} is not one of {}", path, value, Value::Array(choices.clone())));
+            }
+        }
+        if let Some(number) = value.as_f64() {
+            if let Some(minimum) = self.minimum.filter(|minimum| number < *minimum) {
+                errors.push(std::format!("{}: {} is below the minimum {}", path, number, minimum));
+            }
+            if let Some(maximum) = self.maximum.filter(|maximum| number > *maximum) {
+                errors.push(std::format!("{}: {} is above the maximum {}", path, number, maximum));
+            }
+            if let Some(minimum) = self.exclusive_minimum.filter(|minimum| number <= *minimum) {
+                errors.push(std::format!("{}: {} is not above the exclusive minimum {}", path, number, minimum));
+            }
+            if let Some(maximum) = self.exclusive_maximum.filter(|maximum| number >= *maximum) {
+                errors.push(std::format!("{}: {} is not below the exclusive maximum {}", path, number, maximum));
+            }
+        }
+
+        match value {
+            Value::String(text) => {
+                let length = text.chars().count();
+                if let Some(min_length) = self.min_length.filter(|min_length| length < *min_length) {
+                    errors.push(std::format!("{}: length {} is below the minimum {}", path, length, min_length));
+                }
+                if let Some(max_length) = self.max_length.filter(|max_length| length > *max_length) {
+                    errors.push(std::format!("{}: length {} is above the maximum {}", path, length, max_length));
+                }
+            }
+            Value::Array(items) => {
+                if let Some(min_items) = self.min_items.filter(|min_items| items.len() < *min_items) {
+                    errors.push(std::format!("{}: {} items is below the minimum {}", path, items.len(), min_items));
+                }
+                if let Some(max_items) = self.max_items.filter(|max_items| items.len() > *max_items) {
+                    errors.push(std::format!("{}: {} items is above the maximum {}", path, items.len(), max_items));
+                }
+                if let Some(schema) = &self.items {
+                    for (i, item) in items.iter().enumerate() {
+                        schema.collect_errors(item, &std::format!("{}[{}]", path, i), errors);
+                    }
+                }
+            }
+            Value::Object(fields) => self.collect_object_errors(fields, path, errors),
+            _ => {}
+        }
+    }
+
+    fn collect_object_errors(&self, fields: &Map<String, Value>, path: &str, errors: &mut Vec<String>) {
+        for name in &self.required {
+            if !fields.contains_key(name) {
+                errors.push(std::format!("{}.{}: missing required field", path, name));
+            }
+        }
+        for (name, field) in fields {
+            match self.properties.get(name) {
+                Some(property) => property.collect_errors(field, &std::format!("{}.{}", path, name), errors),
+                None if !self.additional_properties => {
+                    errors.push(std::format!("{}.{}: unknown field", path, name));
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// A worker described in a JSON or YAML file rather than in Rust: its endpoint, the
+/// input it accepts and the output it returns.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BackendDefinition {
+    pub name: String,
+    pub endpoint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub input: Schema,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<Schema>,
+}
+
+impl BackendDefinition {
+    pub fn from_json_str(source: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(source)?)
+    }
+
+    pub fn from_yaml_str(source: &str) -> Result<Self, Error> {
+        Ok(serde_yaml::from_str(source)?)
+    }
+
+    /// Loads a `.json`, `.yaml` or `.yml` definition.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json_str(&source),
+            Some("yaml" | "yml") => Self::from_yaml_str(&source),
+            _ => Err(Error::msg(std::format!("Unknown backend definition format '{}'", path.display()))),
+        }
+    }
+
+    /// `input` with defaults filled in, or every way it breaks the input schema.
+    pub fn prepare(&self, mut input: Value) -> Result<Value, Error> {
+        self.input.apply_defaults(&mut input);
+        self.input
+            .validate(&input, "input")
+            .map_err(|e| Error::msg(std::format!("Invalid request for '{}': {}", self.name, e)))?;
+        Ok(input)
+    }
+
+    /// Validates `input`, runs it on the definition's endpoint and checks the output
+    /// against the output schema, if there is one.
+    pub async fn request(&self, client: &RunpodClient<Custom>, input: Value) -> Result<CustomResult, Error> {
+        let input = self.prepare(input)?;
+        let queued = job::submit(&client.api_base, &self.endpoint, &client.api_key, &input).await?;
+        let result = job::wait::<Value>(
+            &client.api_base,
+            &self.endpoint,
+            &client.api_key,
+            &queued.id,
+            client.poll_time
+        ).await?;
+        if let (Some(schema), Some(output)) = (&self.output, &result.output) {
+            schema
+                .validate(output, "output")
+                .map_err(|e| Error::msg(std::format!("Unexpected output from '{}': {}", self.name, e)))?;
+        }
+        Ok(result)
+    }
+}
+
+/// Backend definitions by name, typically loaded from a directory of schema files.
+#[derive(Debug, Clone, Default)]
+pub struct BackendDefinitions {
+    definitions: BTreeMap<String, BackendDefinition>,
+}
+
+impl BackendDefinitions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads every `.json`, `.yaml` and `.yml` file in `dir`.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let mut definitions = Self::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if matches!(path.extension().and_then(|e| e.to_str()), Some("json" | "yaml" | "yml")) {
+                let definition = BackendDefinition::from_file(&path).map_err(|e|
+                    Error::msg(std::format!("{}: {}", path.display(), e))
+                )?;
+                definitions.insert(definition)?;
+            }
+        }
+        Ok(definitions)
+    }
+
+    pub fn insert(&mut self, definition: BackendDefinition) -> Result<(), Error> {
+        if self.definitions.contains_key(&definition.name) {
+            return Err(Error::msg(std::format!("Backend '{}' is defined twice", definition.name)));
+        }
+        self.definitions.insert(definition.name.clone(), definition);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&BackendDefinition, Error> {
+        self.definitions.get(name).ok_or(Error::msg(std::format!("Unknown backend '{}'", name)))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.definitions.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::custom::Custom,
+        client::client::{ RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ BackendDefinition, BackendDefinitions };
+
+    #[test]
+    fn test_prepare_input() {
+        let upscaler = BackendDefinition::from_file("tests/fixtures/schema/upscaler.yaml").unwrap();
+        assert_eq!(upscaler.endpoint, "upscaler-endpoint");
+
+        let input = upscaler.prepare(json!({ "image": "https://example.com/cat.png" })).unwrap();
+        assert_eq!(input, json!({ "image": "https://example.com/cat.png", "scale": 2, "face_enhance": false }));
+
+        let tags = json!(["a", 1, "c", "d", "e", "f", "g", "h", "i"]);
+        let error = upscaler
+            .prepare(json!({ "scale": 8, "mode": "slow", "tags": tags, "extra": true }))
+            .unwrap_err()
+            .to_string();
+        for expected in [
+            "input.image: missing required field",
+            "input.scale: 8 is above the maximum 4",
+            "input.mode: \"slow\" is not one of [\"fast\",\"best\"]",
+            "input.tags: 9 items is above the maximum 8",
+            "input.tags[1]: expected String, got 1",
+            "input.extra: unknown field",
+        ] {
+            assert!(error.contains(expected), "{} not in {}", expected, error);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_keyword_is_rejected() {
+        let definition = |keyword: &str| {
+            BackendDefinition::from_json_str(
+                &json!({
+                    "name": "tagger",
+                    "endpoint": "tagger-endpoint",
+                    "input": {
+                        "type": "object",
+                        "properties": { "score": { "type": "number", keyword: 0 } }
+                    }
+                }).to_string()
+            )
+        };
+
+        let tagger = definition("exclusiveMinimum").unwrap();
+        assert!(tagger.prepare(json!({ "score": 0 })).is_err());
+        assert!(tagger.prepare(json!({ "score": 0.5 })).is_ok());
+
+        let error = definition("multipleOf").unwrap_err().to_string();
+        assert!(error.contains("unknown field `multipleOf`"), "{}", error);
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let definitions = BackendDefinitions::from_dir("tests/fixtures/schema").unwrap();
+        assert_eq!(definitions.names(), vec!["captioner", "upscaler"]);
+        assert!(definitions.get("captioner").unwrap().prepare(json!({ "image": "x", "max_words": 0 })).is_err());
+        assert!(definitions.get("unknown").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_checks_output() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/captioner-endpoint/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/captioner-endpoint/status/job-1/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(
+                    json!({ "id": "job-1", "status": "COMPLETED", "output": { "caption": 42 } })
+                )
+            )
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(Custom)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .build();
+        let captioner = BackendDefinition::from_file("tests/fixtures/schema/captioner.json").unwrap();
+
+        assert!(captioner.request(&client, json!({})).await.is_err());
+        assert!(server.received_requests().await.unwrap().is_empty());
+
+        let error = captioner.request(&client, json!({ "image": "x" })).await.unwrap_err();
+        assert!(error.to_string().contains("output.caption: expected String, got 42"));
+        let sent = server.received_requests().await.unwrap();
+        assert_eq!(sent[0].body_json::<Value>().unwrap(), json!({ "input": { "image": "x", "max_words": 20 } }));
+    }
+}
@@ -1,277 +1,238 @@
-#![allow(non_snake_case)]
+use super::{
+    backend::{ RunpodBackend, RunpodParams },
+    diffusion::{ diffusion_setters, DiffusionModel, DiffusionParams, DiffusionParamsCore, DiffusionResult },
+    image::ImageInput,
+};
 
-use std::time::Duration;
+use std::{ fmt, str::FromStr };
 
-use async_trait::async_trait;
-use log::info;
-use serde_json::{ json, Value };
+use serde::{ Deserialize, Serialize };
 
-use crate::client::client::{ RunpodClient, RunpodClientAPI, DEFAULT_API_BASE };
+pub use super::diffusion::DiffusionOutputFetch as StableDiffusionXLOutputFetch;
 
-use super::backend::{ RunpodBackend, RunpodParams };
+pub struct StableDiffusionXL;
 
-use reqwest::Url;
+/// `(width, height)` of the landscape and square buckets SDXL was trained on; portrait
+/// buckets are the same sizes transposed.
+pub const SDXL_BUCKETS: [(u64, u64); 21] = [
+    (1024, 1024),
+    (1024, 960),
+    (1088, 960),
+    (1088, 896),
+    (1152, 896),
+    (1152, 832),
+    (1216, 832),
+    (1280, 768),
+    (1344, 768),
+    (1344, 704),
+    (1408, 704),
+    (1472, 704),
+    (1536, 640),
+    (1600, 640),
+    (1664, 576),
+    (1728, 576),
+    (1792, 576),
+    (1856, 512),
+    (1920, 512),
+    (1984, 512),
+    (2048, 512),
+];
+
+/// The trained bucket whose aspect ratio is closest to `width / height`.
+pub fn snap_to_bucket(width: f64, height: f64) -> (u64, u64) {
+    let target = (width / height).ln();
+    SDXL_BUCKETS.iter()
+        .flat_map(|&(w, h)| [(w, h), (h, w)])
+        .min_by(|a, b| {
+            let da = ((a.0 as f64) / (a.1 as f64)).ln() - target;
+            let db = ((b.0 as f64) / (b.1 as f64)).ln() - target;
+            da.abs().total_cmp(&db.abs())
+        })
+        .unwrap_or((1024, 1024))
+}
 
-use anyhow::Error;
+/// Schedulers the SDXL worker accepts. `Custom` passes any other name through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StableDiffusionXLScheduler {
+    PNDM,
+    KLMS,
+    DDIM,
+    KEuler,
+    DPMSolverMultistep,
+    Custom(String),
+}
 
-use serde::{ Deserialize, Serialize };
+impl StableDiffusionXLScheduler {
+    pub fn as_str(&self) -> &str {
+        match self {
+            StableDiffusionXLScheduler::PNDM => "PNDM",
+            StableDiffusionXLScheduler::KLMS => "KLMS",
+            StableDiffusionXLScheduler::DDIM => "DDIM",
+            StableDiffusionXLScheduler::KEuler => "K_EULER",
+            StableDiffusionXLScheduler::DPMSolverMultistep => "DPMSolverMultistep",
+            StableDiffusionXLScheduler::Custom(name) => name.as_str(),
+        }
+    }
+}
 
-pub struct StableDiffusionXL;
+impl fmt::Display for StableDiffusionXLScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-#[async_trait]
-pub trait StableDiffusionXLOutputFetch {
-    async fn fetch(&self) -> Result<Vec<u8>, Error>;
+impl FromStr for StableDiffusionXLScheduler {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "PNDM" => StableDiffusionXLScheduler::PNDM,
+            "KLMS" => StableDiffusionXLScheduler::KLMS,
+            "DDIM" => StableDiffusionXLScheduler::DDIM,
+            "K_EULER" => StableDiffusionXLScheduler::KEuler,
+            "DPMSolverMultistep" => StableDiffusionXLScheduler::DPMSolverMultistep,
+            other => StableDiffusionXLScheduler::Custom(other.to_owned()),
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StableDiffusionXLOutput {
+    #[serde(default)]
     pub image_url: String,
+    #[serde(default)]
     pub images: Vec<String>,
-    pub seed: i64,
+    /// Left out by workers that don't report the seed they used.
+    #[serde(default)]
+    pub seed: Option<i64>,
 }
 
-#[async_trait]
 impl StableDiffusionXLOutputFetch for StableDiffusionXLOutput {
-    async fn fetch(&self) -> Result<Vec<u8>, Error> {
-        let url = Url::parse(self.image_url.as_str()).unwrap_or(Url::parse(DEFAULT_API_BASE).unwrap());
-        reqwest::Client
-            ::new()
-            .get(url)
-            .send().await?
-            .bytes().await
-            .map(|x| x.to_vec())
-            .map_err(|x| x.into())
+    /// All of `images`, or `image_url` for workers that only return a single image.
+    fn images(&self) -> Vec<&str> {
+        if self.images.is_empty() {
+            vec![self.image_url.as_str()]
+        } else {
+            self.images.iter().map(|i| i.as_str()).collect()
+        }
     }
-}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct StableDiffusionXLResult {
-    pub delayTime: Option<u64>,
-    pub executionTime: Option<u64>,
-    pub id: Option<String>,
-    pub output: Option<StableDiffusionXLOutput>,
-    pub status: Option<String>,
+    fn seed(&self) -> Option<i64> {
+        self.seed
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub type StableDiffusionXLResult = DiffusionResult<StableDiffusionXLOutput>;
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(builder = StableDiffusionXLParamBuilderTrait, declared)]
 pub struct StableDiffusionXLParams {
-    prompt: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    width: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    height: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    guidance_scale: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(flatten)]
+    #[runpod(skip)]
+    core: DiffusionParams,
+    /// Init image for img2img, sent as `image_url`.
+    #[serde(rename = "image_url")]
+    init_image: Option<ImageInput>,
+    #[runpod(range(min = 0.0, max = 1.0))]
     strength: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    num_inference_steps: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[runpod(setter = with_refiner_steps)]
     refiner_inference_steps: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     num_images: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    scheduler: Option<String>,
+    /// Fraction of the steps run by the base model before the refiner takes over.
+    #[runpod(range(min = 0.0, max = 1.0))]
+    high_noise_frac: Option<f64>,
 }
 
-impl StableDiffusionXLParams {
-    pub fn new() -> Self {
-        Self {
-            prompt: Default::default(),
-            width: None,
-            height: None,
-            guidance_scale: None,
-            strength: None,
-            num_inference_steps: None,
-            refiner_inference_steps: None,
-            num_images: None,
-            scheduler: None,
-        }
-    }
+impl RunpodBackend for StableDiffusionXL {}
+
+impl DiffusionModel for StableDiffusionXL {
+    const ENDPOINT: &'static str = "sdxl";
+    const NAME: &'static str = "SDXL";
+    const MAX_PIXELS: u64 = 1536 * 1536;
+    type Params = StableDiffusionXLParams;
+    type Output = StableDiffusionXLOutput;
 }
 
-impl Default for StableDiffusionXLParams {
-    fn default() -> Self {
-        Self {
-            prompt: Default::default(),
-            width: None,
-            height: None,
-            guidance_scale: None,
-            strength: None,
-            num_inference_steps: None,
-            refiner_inference_steps: None,
-            scheduler: None,
-            num_images: None,
-        }
+impl DiffusionParamsCore for StableDiffusionXLParams {
+    fn core(&self) -> &DiffusionParams {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut DiffusionParams {
+        &mut self.core
     }
 }
 
-impl RunpodBackend for StableDiffusionXL {}
+pub trait StableDiffusionXLParamBuilderTrait: DiffusionParamsCore + Sized {
+    diffusion_setters!(StableDiffusionXLScheduler);
 
-impl RunpodParams for StableDiffusionXLParams {}
+    /// Sets the resolution to the trained bucket nearest to a `width:height` aspect ratio.
+    fn with_aspect_ratio(self, width: f64, height: f64) -> Self {
+        let (width, height) = snap_to_bucket(width, height);
+        self.with_resolution(width, height)
+    }
 
-pub trait StableDiffusionXLParamBuilderTrait {
-    fn with_prompt(self, prompt: String) -> Self;
-    fn with_resolution(self, width: u64, height: u64) -> Self;
-    fn with_guidance_scale(self, cfg_scale: f64) -> Self;
     fn with_refiner_steps(self, refiner_steps: u64) -> Self;
-    fn with_steps(self, steps: u64) -> Self;
     fn with_num_images(self, output_num: u64) -> Self;
     fn with_strength(self, strength: f64) -> Self;
-    fn with_scheduler(self, scheduler: String) -> Self;
+    fn with_high_noise_frac(self, high_noise_frac: f64) -> Self;
+    fn with_init_image(self, init_image: ImageInput) -> Self;
     fn build(self) -> StableDiffusionXLParams;
 }
 
-impl StableDiffusionXLParamBuilderTrait for StableDiffusionXLParams {
-    fn build(self) -> StableDiffusionXLParams {
-        StableDiffusionXLParams {
-            prompt: self.prompt,
-            width: self.width,
-            height: self.height,
-            guidance_scale: self.guidance_scale,
-            num_inference_steps: self.num_inference_steps,
-            scheduler: self.scheduler,
-            strength: self.strength,
-            refiner_inference_steps: self.refiner_inference_steps,
-            num_images: self.num_images,
-        }
-    }
-
-    fn with_prompt(mut self, prompt: String) -> Self {
-        self.prompt = prompt;
-        self
-    }
-
-    fn with_resolution(mut self, width: u64, height: u64) -> Self {
-        self.width = Some(width);
-        self.height = Some(height);
-        self
-    }
-
-    fn with_guidance_scale(mut self, cfg_scale: f64) -> Self {
-        self.guidance_scale = Some(cfg_scale);
-        self
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use crate::{
+        backend::diffusion::DiffusionParamsCore,
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{
+        snap_to_bucket,
+        StableDiffusionXL,
+        StableDiffusionXLParamBuilderTrait,
+        StableDiffusionXLParams,
+        StableDiffusionXLScheduler,
+    };
+
+    #[test]
+    fn test_snap_aspect_ratio() {
+        assert_eq!(snap_to_bucket(1.0, 1.0), (1024, 1024));
+        assert_eq!(snap_to_bucket(16.0, 9.0), (1344, 768));
+        assert_eq!(snap_to_bucket(9.0, 16.0), (768, 1344));
+        assert_eq!(snap_to_bucket(3.0, 2.0), (1216, 832));
+
+        let params = StableDiffusionXLParams::new().with_aspect_ratio(4.0, 3.0);
+        assert_eq!((params.core().width, params.core().height), (Some(1152), Some(896)));
     }
 
-    fn with_steps(mut self, steps: u64) -> Self {
-        self.num_inference_steps = Some(steps);
-        self
+    #[test]
+    fn test_scheduler_names() {
+        let params = StableDiffusionXLParams::new().with_scheduler(StableDiffusionXLScheduler::KEuler);
+        assert_eq!(params.core().scheduler.as_deref(), Some("K_EULER"));
+        assert_eq!("DDIM".parse::<StableDiffusionXLScheduler>().unwrap(), StableDiffusionXLScheduler::DDIM);
+        assert_eq!(
+            "UniPC".parse::<StableDiffusionXLScheduler>().unwrap(),
+            StableDiffusionXLScheduler::Custom("UniPC".to_owned())
+        );
     }
 
-    fn with_refiner_steps(mut self, refiner_steps: u64) -> Self {
-        self.refiner_inference_steps = Some(refiner_steps);
-        self
-    }
-
-    fn with_strength(mut self, strength: f64) -> Self {
-        self.strength = Some(strength);
-        self
-    }
+    #[tokio::test]
+    async fn test_invalid_resolution_is_rejected_locally() {
+        let client = RunpodClientBuilder::new(StableDiffusionXL)
+            .with_api_base(Url::parse("http://127.0.0.1:9/").unwrap())
+            .build();
 
-    fn with_scheduler(mut self, scheduler: String) -> Self {
-        self.scheduler = Some(scheduler);
-        self
-    }
-    
-    fn with_num_images(mut self, output_num: u64) -> Self {
-        self.num_images = Some(output_num);
-        self
-    }
-    
-}
+        let err = client.request(StableDiffusionXLParams::new().with_resolution(1020, 1024)).await.unwrap_err();
+        assert!(err.to_string().contains("multiple of 8"));
 
-async fn queue_job(
-    api_base: Url,
-    api_key: String,
-    params: StableDiffusionXLParams
-) -> Result<Value, Error> {
-    let machine_run_async: Url = api_base
-        .join("sdxl/")
-        .unwrap()
-        .join("run")
-        .unwrap();
-    let client = reqwest::Client::new();
-
-    let request = json!({
-        "input": params
-    });
-
-    info!("SDXL Request: {:#?}", request);
-
-    let result = client
-        .post(machine_run_async)
-        .bearer_auth(api_key)
-        .json(&request)
-        .send().await?
-        .json::<Value>().await
-        .map_err(|x| x.into());
-    
-    info!("SDXL Result: {:#?}", result);
-
-    result
-}
-
-async fn wait_for_completion(
-    job_id: &str,
-    api_base: Url,
-    api_key: String,
-    poll_time: Duration
-) -> Result<StableDiffusionXLResult, Error> {
-    let machine_status_async: Url = api_base
-        .join("sdxl/")?
-        .join("status/")?
-        .join(std::format!("{}/", job_id).as_str())?;
-
-    let client = reqwest::Client::new();
-
-    loop {
-        let response = client
-            .get(machine_status_async.clone())
-            .bearer_auth(api_key.clone())
-            .send().await?
-            .json::<StableDiffusionXLResult>().await?;
-
-        match response.status.as_ref().expect("Didn't get status from job queue.").as_str() {
-            "COMPLETED" => {
-                // All done
-                break Ok(response);
-            }
-            "FAILED" => {
-                break Err(Error::msg("RunPod job status FAILED."));
-            }
-            _ => {
-                tokio::time::sleep(poll_time).await;
-            }
-        }
-    }
-}
+        let err = client.request(StableDiffusionXLParams::new().with_resolution(2048, 2048)).await.unwrap_err();
+        assert!(err.to_string().contains("pixel limit"));
 
-#[async_trait]
-impl RunpodClientAPI<StableDiffusionXLParams, Result<StableDiffusionXLResult, Error>>
-for RunpodClient<StableDiffusionXL> {
-    async fn request(
-        &self,
-        params: StableDiffusionXLParams
-    ) -> Result<StableDiffusionXLResult, Error> {
-        let response = queue_job(self.api_base.clone(), self.api_key.clone(), params).await?;
-        let comp: Result<StableDiffusionXLResult, Error> = match
-            response.get("status").unwrap().as_str()
-        {
-            Some("IN_QUEUE") => {
-                    //Queued successfully
-                    let id = response["id"].as_str().unwrap();
-                    wait_for_completion(
-                        id,
-                        self.api_base.clone(),
-                        self.api_key.clone(),
-                        self.poll_time
-                    ).await
-            }
-            _ => {
-                //Something happened
-                Err(Error::msg("Couldn't queue prompt!"))
-            }
-        };
-        comp
+        let side = u64::MAX - 7;
+        let err = client.request(StableDiffusionXLParams::new().with_resolution(side, side)).await.unwrap_err();
+        assert!(err.to_string().contains("pixel limit"));
     }
 }
@@ -0,0 +1,350 @@
+use std::{ fmt::Display, path::Path };
+
+use anyhow::Error;
+use futures::{ stream, StreamExt };
+use image::{ imageops::FilterType, Rgba, RgbaImage };
+use log::warn;
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::diffusion::{ DiffusionModel, DiffusionOutputFetch, DiffusionParams, DiffusionParamsCore, DiffusionResult };
+
+/// One setting a sweep varies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SweepValue {
+    Seed(i64),
+    GuidanceScale(f64),
+    Steps(u64),
+    Scheduler(String),
+}
+
+impl SweepValue {
+    fn apply(&self, core: &mut DiffusionParams) {
+        match self {
+            SweepValue::Seed(seed) => {
+                core.seed = Some(*seed);
+            }
+            SweepValue::GuidanceScale(scale) => {
+                core.guidance_scale = Some(*scale);
+            }
+            SweepValue::Steps(steps) => {
+                core.num_inference_steps = Some(*steps);
+            }
+            SweepValue::Scheduler(scheduler) => {
+                core.scheduler = Some(scheduler.clone());
+            }
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            SweepValue::Seed(seed) => std::format!("seed={}", seed),
+            SweepValue::GuidanceScale(scale) => std::format!("guidance_scale={}", scale),
+            SweepValue::Steps(steps) => std::format!("steps={}", steps),
+            SweepValue::Scheduler(scheduler) => std::format!("scheduler={}", scheduler),
+        }
+    }
+}
+
+/// One combination of a sweep: its position along each axis and the params to send.
+#[derive(Debug, Clone)]
+pub struct SweepCell<P> {
+    pub index: Vec<usize>,
+    pub labels: Vec<String>,
+    pub params: P,
+}
+
+pub struct SweepEntry<R> {
+    pub index: Vec<usize>,
+    pub labels: Vec<String>,
+    pub result: Result<R, Error>,
+}
+
+/// Results of a sweep in row-major order over its axes; the last axis varies fastest.
+pub struct SweepGrid<R> {
+    pub axes: Vec<Vec<SweepValue>>,
+    pub entries: Vec<SweepEntry<R>>,
+}
+
+impl<R> SweepGrid<R> {
+    pub fn get(&self, index: &[usize]) -> Option<&SweepEntry<R>> {
+        self.entries.iter().find(|e| e.index == index)
+    }
+}
+
+/// A cartesian grid of diffusion runs around a base set of params.
+#[derive(Debug, Clone)]
+pub struct Sweep<P> {
+    base: P,
+    axes: Vec<Vec<SweepValue>>,
+}
+
+impl<P: DiffusionParamsCore + Clone> Sweep<P> {
+    pub fn new(base: P) -> Self {
+        Self {
+            base,
+            axes: Vec::new(),
+        }
+    }
+
+    pub fn over(mut self, values: Vec<SweepValue>) -> Self {
+        if !values.is_empty() {
+            self.axes.push(values);
+        }
+        self
+    }
+
+    pub fn seeds(self, seeds: Vec<i64>) -> Self {
+        self.over(seeds.into_iter().map(SweepValue::Seed).collect())
+    }
+
+    pub fn guidance_scales(self, scales: Vec<f64>) -> Self {
+        self.over(scales.into_iter().map(SweepValue::GuidanceScale).collect())
+    }
+
+    pub fn steps(self, steps: Vec<u64>) -> Self {
+        self.over(steps.into_iter().map(SweepValue::Steps).collect())
+    }
+
+    /// Takes the model's scheduler enum or plain names.
+    pub fn schedulers<S: Display>(self, schedulers: Vec<S>) -> Self {
+        self.over(
+            schedulers
+                .into_iter()
+                .map(|s| SweepValue::Scheduler(s.to_string()))
+                .collect()
+        )
+    }
+
+    /// Number of runs. A sweep without axes still runs the base params once.
+    pub fn len(&self) -> usize {
+        self.axes.iter().map(|axis| axis.len()).product()
+    }
+
+    /// Whether the sweep has no runs at all. A sweep without axes still runs once.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether no axis has been added, so the sweep only runs the base params.
+    pub fn varies_nothing(&self) -> bool {
+        self.axes.is_empty()
+    }
+
+    /// Every combination of the axes, applied to a copy of the base params.
+    pub fn expand(&self) -> Vec<SweepCell<P>> {
+        let mut cells = vec![SweepCell { index: Vec::new(), labels: Vec::new(), params: self.base.clone() }];
+        for axis in self.axes.iter() {
+            cells = cells
+                .into_iter()
+                .flat_map(|cell| {
+                    axis.iter()
+                        .enumerate()
+                        .map(move |(i, value)| {
+                            let mut next = cell.clone();
+                            value.apply(next.params.core_mut());
+                            next.index.push(i);
+                            next.labels.push(value.label());
+                            next
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+        }
+        cells
+    }
+
+    /// Submits every combination, at most `concurrency` at a time. A failed run is
+    /// recorded in its cell rather than stopping the sweep.
+    pub async fn run<M>(
+        &self,
+        client: &RunpodClient<M>,
+        concurrency: usize
+    ) -> SweepGrid<DiffusionResult<M::Output>>
+        where M: DiffusionModel<Params = P>
+    {
+        let entries = stream
+            ::iter(self.expand())
+            .map(|cell| async move {
+                SweepEntry {
+                    result: client.request(cell.params).await,
+                    index: cell.index,
+                    labels: cell.labels,
+                }
+            })
+            .buffered(concurrency.max(1))
+            .collect::<Vec<_>>().await;
+
+        SweepGrid {
+            axes: self.axes.clone(),
+            entries,
+        }
+    }
+}
+
+impl<O: DiffusionOutputFetch> SweepGrid<DiffusionResult<O>> {
+    /// Composes the first image of every cell into one PNG, `tile` pixels per cell. The
+    /// last axis runs along the columns and the remaining axes along the rows. Cells whose
+    /// run failed or whose image couldn't be fetched are left grey.
+    pub async fn contact_sheet<Q: AsRef<Path>>(&self, path: Q, tile: u32) -> Result<(), Error> {
+        if tile == 0 {
+            return Err(Error::msg("Contact sheet tiles must be at least 1 pixel"));
+        }
+        let columns = self.axes.last().map(|axis| axis.len()).unwrap_or(1) as u32;
+        let rows = (self.entries.len() as u32).div_ceil(columns);
+        let mut sheet = RgbaImage::from_pixel(columns * tile, rows * tile, Rgba([128, 128, 128, 255]));
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let output = match entry.result.as_ref().ok().and_then(|r| r.output.as_ref()) {
+                Some(output) => output,
+                None => {
+                    continue;
+                }
+            };
+            let thumb = match output.fetch().await.and_then(|bytes| Ok(image::load_from_memory(&bytes)?)) {
+                Ok(image) => image.resize(tile, tile, FilterType::Triangle).to_rgba8(),
+                Err(e) => {
+                    warn!("Sweep cell {} left empty: {}", entry.labels.join(", "), e);
+                    continue;
+                }
+            };
+
+            let x = ((i as u32) % columns) * tile + (tile - thumb.width()) / 2;
+            let y = ((i as u32) / columns) * tile + (tile - thumb.height()) / 2;
+            image::imageops::overlay(&mut sheet, &thumb, x as i64, y as i64);
+        }
+
+        sheet.save(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::{
+            diffusion::DiffusionResult,
+            sdxl::{
+                StableDiffusionXL,
+                StableDiffusionXLOutput,
+                StableDiffusionXLParamBuilderTrait,
+                StableDiffusionXLParams,
+                StableDiffusionXLScheduler,
+            },
+        },
+        client::client::{ RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ Sweep, SweepEntry, SweepGrid, SweepValue };
+
+    // 1x1 transparent PNG
+    const PIXEL: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR4nGNgYGBgAAAABQABpfZFQAAAAABJRU5ErkJggg==";
+
+    #[test]
+    fn test_expand_cartesian_grid() {
+        let sweep = Sweep::new(StableDiffusionXLParams::new().with_prompt("a fox".to_owned()))
+            .seeds(vec![1, 2])
+            .guidance_scales(vec![5.0, 7.5, 9.0]);
+        let cells = sweep.expand();
+
+        assert_eq!(sweep.len(), 6);
+        assert!(!sweep.is_empty());
+        assert!(!sweep.varies_nothing());
+        let base_only = Sweep::new(StableDiffusionXLParams::new());
+        assert!(base_only.varies_nothing());
+        assert_eq!(base_only.len(), 1);
+        assert!(!base_only.is_empty());
+        assert_eq!(cells[4].index, vec![1, 1]);
+        assert_eq!(cells[4].labels, vec!["seed=2", "guidance_scale=7.5"]);
+        assert_eq!(
+            serde_json::to_value(&cells[4].params).unwrap(),
+            json!({ "prompt": "a fox", "seed": 2, "guidance_scale": 7.5 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_sweep_and_compose_sheet() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/sdxl/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/sdxl/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "status": "COMPLETED",
+                "output": { "image_url": std::format!("data:image/png;base64,{}", PIXEL), "images": [], "seed": 1 }
+            })))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(StableDiffusionXL)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .build();
+        let grid = Sweep::new(StableDiffusionXLParams::new().with_prompt("a fox".to_owned()))
+            .steps(vec![10, 20])
+            .schedulers(
+                vec![
+                    StableDiffusionXLScheduler::DDIM,
+                    StableDiffusionXLScheduler::KEuler,
+                    StableDiffusionXLScheduler::PNDM
+                ]
+            )
+            .run(&client, 2).await;
+
+        assert_eq!(grid.entries.len(), 6);
+        assert!(grid.entries.iter().all(|e| e.result.is_ok()));
+        assert_eq!(grid.get(&[1, 2]).unwrap().labels, vec!["steps=20", "scheduler=PNDM"]);
+
+        let mut sent: Vec<(u64, String)> = server
+            .received_requests().await
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.url.path().ends_with("/run"))
+            .map(|r| r.body_json::<Value>().unwrap())
+            .map(|b| (b["input"]["num_inference_steps"].as_u64().unwrap(), b["input"]["scheduler"].as_str().unwrap().to_owned()))
+            .collect();
+        sent.sort();
+        assert_eq!(sent.len(), 6);
+        assert_eq!(sent[5], (20, "PNDM".to_owned()));
+
+        let sheet = std::env::temp_dir().join(std::format!("rpc-sweep-{}.png", std::process::id()));
+        grid.contact_sheet(&sheet, 16).await.unwrap();
+        let composed = image::open(&sheet).unwrap();
+        assert_eq!((composed.width(), composed.height()), (48, 32));
+        std::fs::remove_file(sheet).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unfetchable_cell_is_left_grey() {
+        let output = |image_url: String| DiffusionResult {
+            delayTime: None,
+            executionTime: None,
+            id: None,
+            output: Some(StableDiffusionXLOutput { image_url, images: Vec::new(), seed: Some(1) }),
+            status: Some("COMPLETED".to_owned()),
+            error: None,
+        };
+        let grid = SweepGrid {
+            axes: vec![vec![SweepValue::Seed(1), SweepValue::Seed(2)]],
+            entries: vec![
+                SweepEntry {
+                    index: vec![0],
+                    labels: vec!["seed=1".to_owned()],
+                    result: Ok(output(std::format!("data:image/png;base64,{}", PIXEL))),
+                },
+                SweepEntry { index: vec![1], labels: vec!["seed=2".to_owned()], result: Ok(output("oops".to_owned())) }
+            ],
+        };
+
+        let sheet = std::env::temp_dir().join(std::format!("rpc-sweep-grey-{}.png", std::process::id()));
+        assert!(grid.contact_sheet(&sheet, 0).await.is_err());
+        grid.contact_sheet(&sheet, 4).await.unwrap();
+        let composed = image::open(&sheet).unwrap().to_rgba8();
+        assert_eq!(composed.get_pixel(6, 2).0, [128, 128, 128, 255]);
+        std::fs::remove_file(sheet).unwrap();
+    }
+}
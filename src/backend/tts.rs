@@ -0,0 +1,164 @@
+use std::path::{ Path, PathBuf };
+
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ Deserialize, Serialize };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{
+    audio::{ AudioInfo, AudioInput, AudioOutput },
+    backend::{ RunpodBackend, RunpodParams },
+    job::{ self, JobResult },
+};
+
+pub struct TTS;
+
+impl RunpodBackend for TTS {}
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(builder = TTSParamBuilderTrait)]
+pub struct TTSParams {
+    text: String,
+    /// ISO code of the language the text is in.
+    language: Option<String>,
+    /// Reference recording to clone the voice of.
+    speaker_wav: Option<AudioInput>,
+    /// A voice built into the worker, such as a Bark preset.
+    speaker: Option<String>,
+    speed: Option<f64>,
+}
+
+/// Speech a worker generated. Workers name the field differently, so `audio`,
+/// `audio_base64`, `audio_url` and `wav` are all accepted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(from = "TTSOutputRepr")]
+pub struct TTSOutput {
+    /// Base64, a data URI or a URL.
+    pub audio: String,
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct TTSOutputRepr {
+    #[serde(alias = "audio_base64", alias = "audio_url", alias = "wav")]
+    audio: String,
+    #[serde(default)]
+    sample_rate: Option<u32>,
+}
+
+impl From<TTSOutputRepr> for TTSOutput {
+    fn from(repr: TTSOutputRepr) -> Self {
+        TTSOutput {
+            audio: repr.audio,
+            sample_rate: repr.sample_rate,
+        }
+    }
+}
+
+impl TTSOutput {
+    pub async fn fetch(&self) -> Result<Vec<u8>, Error> {
+        AudioOutput::parse(&self.audio)?.load().await
+    }
+
+    /// Sample rate, channels and duration from the audio's header.
+    pub async fn info(&self) -> Result<AudioInfo, Error> {
+        AudioInfo::parse(&self.fetch().await?)
+    }
+
+    /// Writes the audio as WAV or MP3, whichever the worker produced. See `AudioOutput::save_to`.
+    pub async fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(PathBuf, AudioInfo), Error> {
+        AudioOutput::parse(&self.audio)?.save_to(path).await
+    }
+}
+
+pub type TTSResult = JobResult<TTSOutput>;
+
+#[async_trait]
+impl RunpodClientAPI<TTSParams, Result<TTSResult, Error>> for RunpodClient<TTS> {
+    async fn request(&self, params: TTSParams) -> Result<TTSResult, Error> {
+        if params.text.trim().is_empty() {
+            return Err(Error::msg("TTS request has no text"));
+        }
+        let queued = job::submit(&self.api_base, &self.machine_id, &self.api_key, &params).await?;
+        job::wait(&self.api_base, &self.machine_id, &self.api_key, &queued.id, self.poll_time).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::audio::{ AudioFormat, AudioInput },
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ TTSOutput, TTSParamBuilderTrait, TTSParams, TTS };
+
+    const XTTS: &str = include_str!("../../tests/fixtures/tts/xtts.json");
+
+    #[test]
+    fn test_output_field_names() {
+        for field in ["audio", "audio_base64", "audio_url", "wav"] {
+            let output: TTSOutput = serde_json::from_value(json!({ field: "https://example.com/a.wav" })).unwrap();
+            assert_eq!(output.audio, "https://example.com/a.wav");
+        }
+        let missing = serde_json::from_value::<TTSOutput>(json!({ "sample_rate": 24000 })).unwrap_err();
+        assert!(missing.to_string().contains("missing field `audio`"), "{}", missing);
+    }
+
+    #[test]
+    fn test_speaker_wav_round_trip() {
+        let url = AudioInput::Url("https://example.com/voice.wav".to_owned());
+        for speaker_wav in [url, AudioInput::Base64("UklGR".to_owned())] {
+            let params = TTSParams::new().with_text("Hi".to_owned()).with_speaker_wav(speaker_wav.clone());
+            let value = serde_json::to_value(&params).unwrap();
+            assert_eq!(value["speaker_wav"], speaker_wav.as_str());
+            let parsed: TTSParams = serde_json::from_value(value).unwrap();
+            assert_eq!(parsed.speaker_wav, Some(speaker_wav));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clone_voice_and_save() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/xtts-endpoint/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/xtts-endpoint/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(XTTS))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(TTS)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("xtts-endpoint".to_owned())
+            .build();
+        assert!(client.request(TTSParams::new()).await.is_err());
+
+        let params = TTSParams::new()
+            .with_text("Hello from the other side.".to_owned())
+            .with_language("en".to_owned())
+            .with_speaker_wav(AudioInput::from_path("tests/fixtures/tts/speaker.wav").unwrap())
+            .with_speed(1.1)
+            .build();
+        let output = client.request(params).await.unwrap().output.unwrap();
+        assert_eq!(output.sample_rate, Some(24000));
+
+        let target = std::env::temp_dir().join(std::format!("rpc-tts-{}", std::process::id()));
+        let (written, info) = output.save_to(&target).await.unwrap();
+        assert_eq!(written, target.with_extension("wav"));
+        assert_eq!((info.format, info.sample_rate, info.channels, info.duration), (AudioFormat::Wav, 24000, 1, 0.5));
+        std::fs::remove_file(written).unwrap();
+
+        let sent = server.received_requests().await.unwrap();
+        let body = sent[0].body_json::<Value>().unwrap();
+        assert_eq!(body["input"]["text"], "Hello from the other side.");
+        assert!(body["input"]["speaker_wav"].as_str().unwrap().starts_with("UklGR"));
+        assert_eq!(body["input"]["speed"], 1.1);
+    }
+}
@@ -1,37 +1,84 @@
 #![allow(non_snake_case)]
 
-use std::{ collections::HashMap, time::Duration };
-
 use async_trait::async_trait;
-use log::info;
-use serde_json::{json, Value};
+use serde_json::Value;
 
 use crate::client::client::{ RunpodClient, RunpodClientAPI };
 
-use super::backend::{ RunpodBackend, RunpodParams };
+use super::{ backend::{ ParamFields, RunpodBackend, RunpodParams }, job::{ self, JobResult } };
 
-use reqwest::Url;
+use self::{
+    content::{ ChatContent, ContentPart },
+    logprobs::{ deserialize_prompt_logprobs, TokenLogprob },
+    output::{ deserialize_output, CompletionOutput, CompletionRepr },
+};
 
 use anyhow::Error;
 
-use serde::{ Deserialize, Serialize };
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+
+pub mod content;
+pub mod guided;
+pub mod logprobs;
+pub mod openai;
+pub mod output;
+pub mod presets;
+pub mod registry;
+pub mod tools;
+#[cfg(feature = "tokenizers")]
+pub mod tokens;
 
 pub struct VLLM;
 
+/// One generated sequence. Deserializes from the native `tokens` list as well as the
+/// OpenAI style `text` and `message` shapes newer workers return.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "CompletionRepr")]
 pub struct Completion { 
-    pub tokens: Vec<String>
+    pub tokens: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+impl Completion {
+    /// The generated text, joining the token chunks the worker returned.
+    pub fn text(&self) -> String {
+        self.tokens.concat()
+    }
+
+    /// Log probability of the generated sequence, if logprobs were requested.
+    pub fn sequence_logprob(&self) -> Option<f64> {
+        logprobs::sequence_logprob(self.logprobs.as_deref()?)
+    }
+
+    pub fn perplexity(&self) -> Option<f64> {
+        logprobs::perplexity(self.logprobs.as_deref()?)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CompletionChoice {
     pub choices: Vec<Completion>,
-    pub usage: CompletionUsage
+    #[serde(default)]
+    pub usage: CompletionUsage,
+    /// One entry per prompt token; the first is always empty since nothing precedes it.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_prompt_logprobs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prompt_logprobs: Option<Vec<Option<TokenLogprob>>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct CompletionUsage { 
+    #[serde(alias = "prompt_tokens")]
     pub input: Option<u64>,
+    #[serde(alias = "completion_tokens")]
     pub output: Option<u64>
 }
 
@@ -40,425 +87,378 @@ pub struct VLLMCompletion {
     pub delayTime: Option<u64>,
     pub executionTime: Option<u64>,
     pub id: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_output")]
     pub output: Option<Vec<CompletionChoice>>,
     pub status: Option<String>
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct VLLMSamplingParams {
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    n: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    best_of: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    presence_penalty: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    frequency_penalty: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    repetition_penalty: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    temperature: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    top_p: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    top_k: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    min_p: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    use_beam_search: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    length_penalty: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    early_stopping: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    stop: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    stop_token_ids: Option<Vec<u64>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    ignore_eos: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    max_tokens: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    skip_special_tokens: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    space_between_special_tokens: Option<bool>,
-}
+impl VLLMCompletion {
+    fn from_job(job: JobResult<CompletionOutput>) -> Self {
+        VLLMCompletion {
+            delayTime: job.delayTime,
+            executionTime: job.executionTime,
+            id: job.id,
+            output: job.output.map(|output| output.0),
+            status: job.status,
+        }
+    }
 
-impl VLLMSamplingParams {
-    pub fn new() -> Self {
-        VLLMSamplingParams {
-            n: None,
-            best_of: None,
-            presence_penalty: None,
-            frequency_penalty: None,
-            repetition_penalty: None,
-            temperature: None,
-            top_p: None,
-            top_k: None,
-            min_p: None,
-            use_beam_search: None,
-            length_penalty: None,
-            early_stopping: None,
-            stop: None,
-            stop_token_ids: None,
-            ignore_eos: None,
-            max_tokens: None,
-            skip_special_tokens: None,
-            space_between_special_tokens: None,
+    fn output_batches(&self) -> Result<&Vec<CompletionChoice>, Error> {
+        match self.output.as_ref() {
+            Some(output) if !output.is_empty() => Ok(output),
+            _ => Err(Error::msg("Completion contained no output.")),
         }
     }
-}
-impl Default for VLLMSamplingParams {
-    fn default() -> Self {
-        VLLMSamplingParams {
-            n: None,
-            best_of: None,
-            presence_penalty: None,
-            frequency_penalty: None,
-            repetition_penalty: None,
-            temperature: None,
-            top_p: None,
-            top_k: None,
-            min_p: None,
-            use_beam_search: None,
-            length_penalty: None,
-            early_stopping: None,
-            stop: None,
-            stop_token_ids: None,
-            ignore_eos: None,
-            max_tokens: None,
-            skip_special_tokens: None,
-            space_between_special_tokens: None,
+
+    /// The text of every choice, in choice order. Output streamed in batches is
+    /// joined back together per choice.
+    pub fn texts(&self) -> Result<Vec<String>, Error> {
+        let mut texts: Vec<String> = Vec::new();
+        for batch in self.output_batches()? {
+            for (i, choice) in batch.choices.iter().enumerate() {
+                match texts.get_mut(i) {
+                    Some(text) => text.push_str(&choice.text()),
+                    None => texts.push(choice.text()),
+                }
+            }
         }
+        if texts.is_empty() {
+            return Err(Error::msg("Completion contained no choices."));
+        }
+        Ok(texts)
+    }
+
+    /// The text of the first choice.
+    pub fn text(&self) -> Result<String, Error> {
+        Ok(self.texts()?.swap_remove(0))
+    }
+
+    /// Why generation of the first choice stopped, if the worker reported it.
+    pub fn finish_reason(&self) -> Result<Option<String>, Error> {
+        let batch = self.output_batches()?.last().unwrap();
+        let choice = batch.choices
+            .first()
+            .ok_or(Error::msg("Completion contained no choices."))?;
+        Ok(choice.finish_reason.clone())
+    }
+
+    /// Token usage of the whole completion. Every streamed batch reports the prompt's
+    /// input tokens again, so input is taken from one batch and only output is summed.
+    pub fn usage(&self) -> Result<CompletionUsage, Error> {
+        let combine = |a: Option<u64>, b: Option<u64>, f: fn(u64, u64) -> u64| match (a, b) {
+            (Some(a), Some(b)) => Some(f(a, b)),
+            (a, b) => a.or(b),
+        };
+        Ok(
+            self.output_batches()?
+                .iter()
+                .fold(CompletionUsage::default(), |acc, batch| CompletionUsage {
+                    input: combine(acc.input, batch.usage.input, u64::max),
+                    output: combine(acc.output, batch.usage.output, |a, b| a + b),
+                })
+        )
     }
 }
 
-pub trait VLLMSamplingParamBuilderTrait {
-    fn with_n(self, n: u64) -> Self;
-    fn with_best_of(self, best_of: u64) -> Self;
-    fn with_presence_penalty(self, presence_penalty: f64) -> Self;
-    fn with_frequency_penalty(self, frequency_penalty: f64) -> Self;
-    fn with_repetition_penalty(self, repetition_penalty: f64) -> Self;
-    fn with_temperature(self, temperature: f64) -> Self;
-    fn with_top_p(self, top_p: f64) -> Self;
-    fn with_top_k(self, top_k: u64) -> Self;
-    fn with_min_p(self, min_p: f64) -> Self;
-    fn use_beam_search(self, beam_search: bool) -> Self;
-    fn with_length_penalty(self, length_penalty: f64) -> Self;
-    fn with_early_stopping(self, early_stopping: String) -> Self;
-    fn with_stop(self, stop_tokens: Vec<String>) -> Self;
-    fn with_stop_token_ids(self, stop_token_ids: Vec<u64>) -> Self;
-    fn with_ignore_eos(self, ignore_eos: bool) -> Self;
-    fn with_max_tokens(self, max_tokens: u64) -> Self;
-    fn with_skip_special_tokens(self, skip_special: bool) -> Self;
-    fn with_space_between_special_tokens(self, space_special: bool) -> Self;
+/// A single chat turn. `content` is optional because assistant turns that only
+/// carry `tool_calls` have no text. It is either plain text or a list of text and
+/// image parts for vision models.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<ChatContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
-impl VLLMSamplingParamBuilderTrait for VLLMSamplingParams {
-    fn with_n(mut self, n: u64) -> Self {
-        self.n = Some(n);
-        self
+impl ChatMessage {
+    pub fn new(role: &str, content: String) -> Self {
+        Self::with_content(role, ChatContent::Text(content))
     }
 
-    fn with_best_of(mut self, best_of: u64) -> Self {
-        self.best_of = Some(best_of);
-        self
-    }
-
-    fn with_presence_penalty(mut self, presence_penalty: f64) -> Self {
-        self.presence_penalty = Some(presence_penalty);
-        self
+    pub fn with_content(role: &str, content: ChatContent) -> Self {
+        Self {
+            role: role.to_owned(),
+            content: Some(content),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
     }
 
-    fn with_frequency_penalty(mut self, frequency_penalty: f64) -> Self {
-        self.frequency_penalty = Some(frequency_penalty);
-        self
+    pub fn system(content: String) -> Self {
+        Self::new("system", content)
     }
 
-    fn with_repetition_penalty(mut self, repetition_penalty: f64) -> Self {
-        self.repetition_penalty = Some(repetition_penalty);
-        self
+    pub fn user(content: String) -> Self {
+        Self::new("user", content)
     }
 
-    fn with_temperature(mut self, temperature: f64) -> Self {
-        self.temperature = Some(temperature);
-        self
+    pub fn assistant(content: String) -> Self {
+        Self::new("assistant", content)
     }
 
-    fn with_top_p(mut self, top_p: f64) -> Self {
-        self.top_p = Some(top_p);
-        self
+    /// A user turn mixing text and images.
+    pub fn user_parts(parts: Vec<ContentPart>) -> Self {
+        Self::with_content("user", ChatContent::Parts(parts))
     }
 
-    fn with_top_k(mut self, top_k: u64) -> Self {
-        self.top_k = Some(top_k);
-        self
+    /// The text of the message without any images.
+    pub fn text(&self) -> Option<String> {
+        self.content.as_ref().map(|content| content.text())
     }
 
-    fn with_min_p(mut self, min_p: f64) -> Self {
-        self.min_p = Some(min_p);
-        self
+    /// An assistant turn requesting the given tool calls.
+    pub fn assistant_tool_calls(content: Option<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            role: "assistant".to_owned(),
+            content: content.map(ChatContent::Text),
+            name: None,
+            tool_calls: Some(tool_calls),
+            tool_call_id: None,
+        }
     }
 
-    fn use_beam_search(mut self, beam_search: bool) -> Self {
-        self.use_beam_search = Some(beam_search);
-        self
+    /// The result of a tool call, fed back to the model.
+    pub fn tool(tool_call_id: String, content: String) -> Self {
+        Self {
+            role: "tool".to_owned(),
+            content: Some(ChatContent::Text(content)),
+            name: None,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id),
+        }
     }
+}
 
-    fn with_length_penalty(mut self, length_penalty: f64) -> Self {
-        self.length_penalty = Some(length_penalty);
-        self
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema describing the function arguments.
+    pub parameters: Value,
+}
 
-    fn with_early_stopping(mut self, early_stopping: String) -> Self {
-        self.early_stopping = Some(early_stopping);
-        self
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
 
-    fn with_stop(mut self, stop_tokens: Vec<String>) -> Self {
-        self.stop = Some(stop_tokens);
-        self
+impl Tool {
+    pub fn function(name: String, description: String, parameters: Value) -> Self {
+        Self {
+            kind: "function".to_owned(),
+            function: FunctionDefinition {
+                name,
+                description: Some(description),
+                parameters,
+            },
+        }
     }
+}
 
-    fn with_stop_token_ids(mut self, stop_token_ids: Vec<u64>) -> Self {
-        self.stop_token_ids = Some(stop_token_ids);
-        self
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
 
-    fn with_ignore_eos(mut self, ignore_eos: bool) -> Self {
-        self.ignore_eos = Some(ignore_eos);
-        self
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NamedFunction {
+    pub name: String,
+}
 
-    fn with_max_tokens(mut self, max_tokens: u64) -> Self {
-        self.max_tokens = Some(max_tokens);
-        self
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NamedToolChoice {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: NamedFunction,
+}
 
-    fn with_skip_special_tokens(mut self, skip_special: bool) -> Self {
-        self.skip_special_tokens = Some(skip_special);
-        self
-    }
+/// Either `"none" | "auto" | "required"` or a specific function the model must call.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(NamedToolChoice),
+}
 
-    fn with_space_between_special_tokens(mut self, space_special: bool) -> Self {
-        self.space_between_special_tokens = Some(space_special);
-        self
+impl ToolChoice {
+    pub fn function(name: String) -> Self {
+        ToolChoice::Function(NamedToolChoice {
+            kind: "function".to_owned(),
+            function: NamedFunction { name },
+        })
     }
-
-    
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct VLLMParams {
-    prompt: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    messages: Option<HashMap<String, String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    apply_chat_template: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    sampling_params: Option<VLLMSamplingParams>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    stream: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    max_batch_size: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    min_batch_size: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    batch_size_growth_factor: Option<u64>,
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, exactly as the model produced them.
+    pub arguments: String,
 }
 
-impl VLLMParams {
-    pub fn new() -> Self {
-        Self {
-            prompt: Default::default(),
-            messages: None,
-            apply_chat_template: None,
-            sampling_params: None,
-            stream: None,
-            max_batch_size: None,
-            min_batch_size: None,
-            batch_size_growth_factor: None,
-        }
+impl FunctionCall {
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_str(&self.arguments).map_err(|e| {
+            Error::msg(
+                std::format!("Invalid arguments for tool '{}': {} ({})", self.name, e, self.arguments)
+            )
+        })
     }
 }
 
-impl Default for VLLMParams {
-    fn default() -> Self {
-        Self {
-            prompt: Default::default(),
-            messages: Default::default(),
-            apply_chat_template: Default::default(),
-            sampling_params: Default::default(),
-            stream: Default::default(),
-            max_batch_size: Default::default(),
-            min_batch_size: Default::default(),
-            batch_size_growth_factor: Default::default(),
-        }
-    }
+fn default_tool_call_kind() -> String {
+    "function".to_owned()
 }
 
-impl RunpodBackend for VLLM {}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "default_tool_call_kind")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
 
-impl RunpodParams for VLLMParams {}
-
-pub trait VLLMParamBuilderTrait {
-    fn with_prompt(self, prompt: String) -> Self;
-    fn with_messages(self, messages: HashMap<String, String>) -> Self;
-    fn apply_chat_template(self, apply_chat_template: bool) -> Self;
-    fn with_sampling_params(self, sampling_params: VLLMSamplingParams) -> Self;
-    fn enable_streaming(self, enable_streaming: bool) -> Self;
-    fn with_max_batch_size(self, max_batch_size: u64) -> Self;
-    fn with_min_batch_size(self, min_batch_size: u64) -> Self;
-    fn with_batch_size_growth_factor(self, batch_size_growth_factor: u64) -> Self;
-    fn build(self) -> VLLMParams;
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, RunpodParams)]
+#[serde(deny_unknown_fields)]
+#[runpod(builder = VLLMSamplingParamBuilderTrait, validate)]
+pub struct VLLMSamplingParams {
+    #[runpod(range(min = 1))]
+    n: Option<u64>,
+    best_of: Option<u64>,
+    #[runpod(range(min = -2.0, max = 2.0))]
+    presence_penalty: Option<f64>,
+    #[runpod(range(min = -2.0, max = 2.0))]
+    frequency_penalty: Option<f64>,
+    #[runpod(range(min = 0.0, exclusive_min))]
+    repetition_penalty: Option<f64>,
+    #[runpod(range(min = 0.0))]
+    temperature: Option<f64>,
+    #[runpod(range(min = 0.0, max = 1.0, exclusive_min))]
+    top_p: Option<f64>,
+    #[runpod(range(min = 1))]
+    top_k: Option<u64>,
+    #[runpod(range(min = 0.0, max = 1.0))]
+    min_p: Option<f64>,
+    #[runpod(setter = use_beam_search)]
+    use_beam_search: Option<bool>,
+    length_penalty: Option<f64>,
+    early_stopping: Option<String>,
+    stop: Option<Vec<String>>,
+    stop_token_ids: Option<Vec<u64>>,
+    ignore_eos: Option<bool>,
+    #[runpod(range(min = 1))]
+    max_tokens: Option<u64>,
+    skip_special_tokens: Option<bool>,
+    space_between_special_tokens: Option<bool>,
+    /// Number of top alternatives to return with each generated token.
+    logprobs: Option<u64>,
+    /// Number of top alternatives to return with each prompt token.
+    prompt_logprobs: Option<u64>,
+    guided_json: Option<Value>,
+    guided_regex: Option<String>,
+    guided_choice: Option<Vec<String>>,
 }
 
-impl VLLMParamBuilderTrait for VLLMParams {
-    fn build(self) -> VLLMParams {
-        VLLMParams {
-            prompt: self.prompt,
-            messages: self.messages,
-            apply_chat_template: self.apply_chat_template,
-            sampling_params: self.sampling_params,
-            stream: self.stream,
-            max_batch_size: self.max_batch_size,
-            min_batch_size: self.min_batch_size,
-            batch_size_growth_factor: self.batch_size_growth_factor,
+impl VLLMSamplingParams {
+    /// Checks ranges and field combinations the same way vLLM does, so bad values are
+    /// rejected locally instead of after a cold start.
+    pub fn validate(&self) -> Result<(), Error> {
+        fn check(ok: bool, msg: &str) -> Result<(), Error> {
+            if ok { Ok(()) } else { Err(Error::msg(std::format!("Invalid sampling params: {}", msg))) }
         }
-    }
 
-    fn with_prompt(mut self, prompt: String) -> Self {
-        self.prompt = prompt;
-        self
-    }
-
-    fn with_messages(mut self, messages: HashMap<String, String>) -> Self {
-        self.messages = Some(messages);
-        self
-    }
+        self.check_ranges().map_err(|e| Error::msg(std::format!("Invalid sampling params: {}", e)))?;
 
-    fn apply_chat_template(mut self, apply_chat_template: bool) -> Self {
-        self.apply_chat_template = Some(apply_chat_template);
-        self
-    }
-
-    fn with_sampling_params(mut self, sampling_params: VLLMSamplingParams) -> Self {
-        self.sampling_params = Some(sampling_params);
-        self
-    }
-
-    fn enable_streaming(mut self, enable_streaming: bool) -> Self {
-        self.stream = Some(enable_streaming);
-        self
-    }
-
-    fn with_max_batch_size(mut self, max_batch_size: u64) -> Self {
-        self.max_batch_size = Some(max_batch_size);
-        self
-    }
-
-    fn with_min_batch_size(mut self, min_batch_size: u64) -> Self {
-        self.min_batch_size = Some(min_batch_size);
-        self
-    }
+        if let Some(best_of) = self.best_of {
+            check(best_of >= self.n.unwrap_or(1), "best_of must be greater than or equal to n")?;
+        }
+        if let Some(stop) = self.stop.as_ref() {
+            check(stop.iter().all(|s| !s.is_empty()), "stop strings must not be empty")?;
+        }
 
-    fn with_batch_size_growth_factor(mut self, batch_size_growth_factor: u64) -> Self {
-        self.batch_size_growth_factor = Some(batch_size_growth_factor);
-        self
+        let guided = [self.guided_json.is_some(), self.guided_regex.is_some(), self.guided_choice.is_some()];
+        check(guided.iter().filter(|g| **g).count() <= 1, "only one of guided_json, guided_regex and guided_choice may be set")?;
+
+        if self.use_beam_search.unwrap_or(false) {
+            check(self.best_of.unwrap_or(1) > 1, "beam search requires best_of greater than 1")?;
+            check(self.temperature.unwrap_or(0.0) == 0.0, "temperature must be 0 when using beam search")?;
+            check(self.top_p.unwrap_or(1.0) == 1.0, "top_p must be 1 when using beam search")?;
+            check(self.top_k.is_none(), "top_k must be unset when using beam search")?;
+            if let Some(early_stopping) = self.early_stopping.as_deref() {
+                check(
+                    matches!(early_stopping, "true" | "false" | "never"),
+                    "early_stopping must be one of \"true\", \"false\" or \"never\""
+                )?;
+            }
+        } else {
+            check(
+                matches!(self.early_stopping.as_deref(), None | Some("false")),
+                "early_stopping only applies to beam search"
+            )?;
+            check(
+                self.length_penalty.unwrap_or(1.0) == 1.0,
+                "length_penalty only applies to beam search"
+            )?;
+        }
+        Ok(())
     }
 }
 
-async fn queue_job(
-    api_base: Url,
-    machine_id: String,
-    api_key: String,
-    params: VLLMParams
-) -> Result<Value, Error> {
-    let machine_run_async: Url = api_base
-        .join(std::format!("{}/", machine_id).as_str())
-        .unwrap()
-        .join("run")
-        .unwrap();
-    let client = reqwest::Client::new();
-
-
-    let request = json!({
-        "input": params
-    });
-
-    info!("VLLM Request: {:#?}", request);
-
-    let resp = client
-        .post(machine_run_async)
-        .bearer_auth(api_key)
-        .json(&request)
-        .send().await?.text().await?;
-    /*let result = resp
-        .json::<Value>().await
-        .map_err(|x| x.into());*/
-    let result = serde_json::from_str::<Value>(&resp).map_err(|x| x.into());
-    
-    info!("VLLM Result: {:#?}", resp);
-
-    result
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(builder = VLLMParamBuilderTrait)]
+pub struct VLLMParams {
+    prompt: String,
+    messages: Option<Vec<ChatMessage>>,
+    tools: Option<Vec<Tool>>,
+    tool_choice: Option<ToolChoice>,
+    #[runpod(setter = apply_chat_template)]
+    apply_chat_template: Option<bool>,
+    sampling_params: Option<VLLMSamplingParams>,
+    #[runpod(setter = enable_streaming)]
+    stream: Option<bool>,
+    max_batch_size: Option<u64>,
+    min_batch_size: Option<u64>,
+    batch_size_growth_factor: Option<u64>,
+    /// Name of a LoRA adapter loaded on the worker.
+    lora_adapter: Option<String>,
 }
 
-async fn wait_for_completion(job_id: &str, api_base: Url, machine_id: String, api_key: String, poll_time: Duration) -> Result<VLLMCompletion, Error> {
-
-    let machine_status_async: Url = api_base
-        .join(std::format!("{}/", machine_id).as_str())
-        .unwrap()
-        .join("status/")
-        .unwrap()
-        .join(std::format!("{}/", job_id).as_str())
-        .unwrap();
-
-    let client = reqwest::Client::new();
-
-    loop {
-        let response = client
-            .get(machine_status_async.clone())
-            .bearer_auth(api_key.clone())
-            .send().await?
-            .json::<VLLMCompletion>().await?;
-
-        match response
-            .status
-            .as_ref()
-            .expect("Didn't get status from job queue.")
-            .as_str() {
-            "COMPLETED" => {
-                // All done
-                break Ok(response);
-            },
-            "FAILED" => {
-                break Err(Error::msg("RunPod job status FAILED."));
-            },
-            _ => {
-                tokio::time::sleep(poll_time).await;
-            }
-        }
-    }
+impl RunpodBackend for VLLM {}
 
+/// Queues `params` on `machine_id` and waits for the result. Shared by the native and
+/// OpenAI routes, and by callers that pick the endpoint per request.
+pub(crate) async fn request_on<P: Serialize>(
+    client: &RunpodClient<VLLM>,
+    machine_id: String,
+    params: P
+) -> Result<VLLMCompletion, Error> {
+    let queued = job::submit(&client.api_base, &machine_id, &client.api_key, &params).await?;
+    let result = job::wait::<CompletionOutput>(
+        &client.api_base,
+        &machine_id,
+        &client.api_key,
+        &queued.id,
+        client.poll_time
+    ).await?;
+    Ok(VLLMCompletion::from_job(result))
 }
 
 #[async_trait]
 impl RunpodClientAPI<VLLMParams, Result<VLLMCompletion, Error>> for RunpodClient<VLLM> {
     async fn request(&self, params: VLLMParams) -> Result<VLLMCompletion, Error> {
-            let response = queue_job(self.api_base.clone(), self.machine_id.clone(), self.api_key.clone(), params).await?;
-            
-            let comp: Result<VLLMCompletion, Error> = match response.get("status").unwrap().as_str() {
-                Some("IN_QUEUE") => async {
-                    //Queued successfully
-                    let id = response["id"].as_str().unwrap();
-                    wait_for_completion(id, self.api_base.clone(), self.machine_id.clone(), self.api_key.clone(), self.poll_time).await
-                }.await,
-                _ => {
-                    //Something happened
-                    Err(Error::msg("Couldn't queue prompt!"))
-                }
-            };
-            comp
+        request_on(self, self.machine_id.clone(), params).await
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,233 @@
+use std::{ fs, io::Cursor, path::Path };
+
+use anyhow::Error;
+use image::{ imageops::FilterType, ImageFormat };
+use serde::{ Deserialize, Serialize };
+
+use crate::backend::image::{ detect_mime, ImageEncoding, ImageInput, MAX_PAYLOAD_BYTES };
+
+/// Message content: plain text, or a list of text and image parts for vision models
+/// such as LLaVA or Qwen-VL.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl ChatContent {
+    /// The text of the message, with the text parts joined by newlines.
+    pub fn text(&self) -> String {
+        match self {
+            ChatContent::Text(text) => text.clone(),
+            ChatContent::Parts(parts) =>
+                parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+        }
+    }
+
+    pub fn image_count(&self) -> usize {
+        match self {
+            ChatContent::Text(_) => 0,
+            ChatContent::Parts(parts) =>
+                parts
+                    .iter()
+                    .filter(|part| matches!(part, ContentPart::ImageUrl { .. }))
+                    .count(),
+        }
+    }
+}
+
+impl From<String> for ChatContent {
+    fn from(text: String) -> Self {
+        ChatContent::Text(text)
+    }
+}
+
+impl From<Vec<ContentPart>> for ChatContent {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        ChatContent::Parts(parts)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImageUrl {
+    /// An http(s) URL or a base64 data URI.
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+}
+
+impl ContentPart {
+    pub fn text(text: String) -> Self {
+        ContentPart::Text { text }
+    }
+
+    /// An image the worker downloads itself.
+    pub fn image_url(url: &str) -> Result<Self, Error> {
+        Ok(Self::image(ImageInput::from_url(url)?))
+    }
+
+    /// An image uploaded inline as a data URI. See `ImageOptions` for the size handling.
+    pub fn image_bytes(bytes: &[u8], options: &ImageOptions) -> Result<Self, Error> {
+        Ok(Self::image(options.prepare(bytes)?))
+    }
+
+    pub fn image_file<P: AsRef<Path>>(path: P, options: &ImageOptions) -> Result<Self, Error> {
+        Self::image_bytes(&fs::read(path)?, options)
+    }
+
+    pub fn image(image: ImageInput) -> Self {
+        ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: image.as_str().to_owned(),
+                detail: None,
+            },
+        }
+    }
+}
+
+/// How local images are prepared before upload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageOptions {
+    /// Images with a longer side than this are downscaled to fit, keeping the aspect ratio.
+    /// `None` sends them at their original size.
+    pub max_dimension: Option<u32>,
+    /// Largest encoded data URI allowed for a single image.
+    pub max_bytes: usize,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            max_dimension: None,
+            max_bytes: MAX_PAYLOAD_BYTES,
+        }
+    }
+}
+
+impl ImageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_dimension(mut self, max_dimension: u32) -> Self {
+        self.max_dimension = Some(max_dimension);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Downscales `bytes` if needed and encodes them as a data URI within `max_bytes`.
+    /// Downscaled images are re-encoded as JPEG if they were JPEG and PNG otherwise.
+    pub fn prepare(&self, bytes: &[u8]) -> Result<ImageInput, Error> {
+        if detect_mime(bytes).is_none() {
+            return Err(Error::msg("Unrecognised image format"));
+        }
+        let max_dimension = match self.max_dimension {
+            Some(max_dimension) => max_dimension,
+            None => {
+                return ImageInput::from_bytes_with_limit(bytes, ImageEncoding::DataUri, self.max_bytes);
+            }
+        };
+
+        let format = image::guess_format(bytes)?;
+        let decoded = image::load_from_memory_with_format(bytes, format)?;
+        if decoded.width().max(decoded.height()) <= max_dimension {
+            return ImageInput::from_bytes_with_limit(bytes, ImageEncoding::DataUri, self.max_bytes);
+        }
+
+        let resized = decoded.resize(max_dimension, max_dimension, FilterType::Triangle);
+        let mut encoded = Cursor::new(Vec::new());
+        match format {
+            ImageFormat::Jpeg => resized.to_rgb8().write_to(&mut encoded, ImageFormat::Jpeg)?,
+            _ => resized.write_to(&mut encoded, ImageFormat::Png)?,
+        }
+        ImageInput::from_bytes_with_limit(encoded.get_ref(), ImageEncoding::DataUri, self.max_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ ImageFormat, RgbImage };
+    use serde_json::json;
+
+    use super::{ ChatContent, ContentPart, ImageOptions };
+
+    fn encoded(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        RgbImage::new(width, height).write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        encoded(width, height, ImageFormat::Png)
+    }
+
+    #[test]
+    fn test_content_serialization() {
+        let content = ChatContent::Parts(
+            vec![
+                ContentPart::text("What is in this image?".to_owned()),
+                ContentPart::image_url("https://example.com/cat.png").unwrap()
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&content).unwrap(),
+            json!([
+                { "type": "text", "text": "What is in this image?" },
+                { "type": "image_url", "image_url": { "url": "https://example.com/cat.png" } }
+            ])
+        );
+        assert_eq!(serde_json::from_value::<ChatContent>(json!("Hi")).unwrap(), ChatContent::Text("Hi".to_owned()));
+        assert_eq!(content.text(), "What is in this image?");
+        assert_eq!(content.image_count(), 1);
+        assert!(ContentPart::image_url("file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_prepare_image() {
+        let original = png(64, 32);
+        let untouched = ImageOptions::new().prepare(&original).unwrap();
+        assert!(untouched.as_str().starts_with("data:image/png;base64,"));
+
+        let downscaled = ImageOptions::new().with_max_dimension(16).prepare(&original).unwrap();
+        let bytes = crate::backend::image::ImageOutput::parse(downscaled.as_str()).unwrap();
+        let crate::backend::image::ImageOutput::Inline(bytes) = bytes else { panic!("expected inline image") };
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+
+        assert!(ImageOptions::new().with_max_bytes(16).prepare(&original).is_err());
+        assert!(ImageOptions::new().prepare(b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_downscale_every_detected_format() {
+        for format in [ImageFormat::Gif, ImageFormat::WebP, ImageFormat::Bmp] {
+            let downscaled = ImageOptions::new().with_max_dimension(16).prepare(&encoded(64, 32, format)).unwrap();
+            assert!(downscaled.as_str().starts_with("data:image/png;base64,"), "{:?}", format);
+        }
+    }
+}
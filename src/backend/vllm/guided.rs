@@ -0,0 +1,130 @@
+use std::fmt;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use schemars::{ schema_for, JsonSchema };
+use serde::de::DeserializeOwned;
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{ VLLMParams, VLLMSamplingParamBuilderTrait, VLLM };
+
+/// Returned when the guided output of a job can't be read back as the requested type.
+/// Carries the raw text so callers can log or repair it.
+#[derive(Debug)]
+pub struct StructuredOutputError {
+    pub raw: String,
+    pub reason: String,
+}
+
+impl fmt::Display for StructuredOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Structured output did not match schema: {} (raw output: {:?})", self.reason, self.raw)
+    }
+}
+
+impl std::error::Error for StructuredOutputError {}
+
+/// Parses the guided output `raw` into `T`.
+pub fn parse_structured<T: DeserializeOwned>(raw: &str) -> Result<T, StructuredOutputError> {
+    serde_json::from_str::<T>(raw.trim()).map_err(|e| StructuredOutputError {
+        raw: raw.to_owned(),
+        reason: e.to_string(),
+    })
+}
+
+#[async_trait]
+pub trait VLLMStructuredRequest {
+    /// Constrains generation with the JSON schema of `T` and deserializes the first
+    /// choice of the completion into it.
+    async fn request_json<T>(&self, params: VLLMParams) -> Result<T, Error>
+        where T: DeserializeOwned + JsonSchema + Send;
+}
+
+#[async_trait]
+impl VLLMStructuredRequest for RunpodClient<VLLM> {
+    async fn request_json<T>(&self, mut params: VLLMParams) -> Result<T, Error>
+        where T: DeserializeOwned + JsonSchema + Send
+    {
+        let schema = serde_json::to_value(schema_for!(T))?;
+        params.sampling_params = Some(
+            params.sampling_params.take().unwrap_or_default().with_guided_json(schema)
+        );
+
+        let raw = self.request(params).await?.text()?;
+
+        parse_structured::<T>(&raw).map_err(Error::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::vllm::{ VLLMParamBuilderTrait, VLLMParams, VLLM },
+        client::client::{ RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ StructuredOutputError, VLLMStructuredRequest };
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Invoice {
+        number: String,
+        total: f64,
+    }
+
+    async fn mock_completion(text: &str) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/vllm-test/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/vllm-test/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-1",
+                "status": "COMPLETED",
+                "output": [{ "choices": [{ "tokens": [text] }], "usage": { "input": 20, "output": 12 } }]
+            })))
+            .mount(&server).await;
+        server
+    }
+
+    #[tokio::test]
+    async fn test_request_json_sends_schema_and_parses() {
+        let server = mock_completion("{\"number\": \"INV-7\", \"total\": 12.5}").await;
+        let client = RunpodClientBuilder::new(VLLM)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("vllm-test".to_owned())
+            .build();
+
+        let invoice = client
+            .request_json::<Invoice>(VLLMParams::new().with_prompt("Extract the invoice.".to_owned())).await
+            .unwrap();
+        assert_eq!(invoice, Invoice { number: "INV-7".to_owned(), total: 12.5 });
+
+        let run: Value = server.received_requests().await.unwrap()[0].body_json().unwrap();
+        let schema = &run["input"]["sampling_params"]["guided_json"];
+        assert_eq!(schema["properties"]["total"]["type"], "number");
+    }
+
+    #[tokio::test]
+    async fn test_request_json_reports_raw_text() {
+        let server = mock_completion("{\"number\": 7}").await;
+        let client = RunpodClientBuilder::new(VLLM)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("vllm-test".to_owned())
+            .build();
+
+        let err = client
+            .request_json::<Invoice>(VLLMParams::new().with_prompt("Extract the invoice.".to_owned())).await
+            .unwrap_err();
+        let err = err.downcast_ref::<StructuredOutputError>().unwrap();
+        assert_eq!(err.raw, "{\"number\": 7}");
+    }
+}
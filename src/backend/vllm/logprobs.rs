@@ -0,0 +1,311 @@
+use std::{ collections::BTreeMap, fmt };
+
+use serde::{ de::{ IgnoredAny, MapAccess, Visitor }, Deserialize, Deserializer, Serialize };
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+}
+
+/// Log probability of one generated (or prompt) token, with the top-k alternatives
+/// considered at that position when the worker reports them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+impl TokenLogprob {
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+/// Sum of the token log probabilities, i.e. the log probability of the whole sequence.
+pub fn sequence_logprob(logprobs: &[TokenLogprob]) -> Option<f64> {
+    if logprobs.is_empty() {
+        return None;
+    }
+    Some(logprobs.iter().map(|t| t.logprob).sum())
+}
+
+pub fn perplexity(logprobs: &[TokenLogprob]) -> Option<f64> {
+    sequence_logprob(logprobs).map(|sum| (-sum / (logprobs.len() as f64)).exp())
+}
+
+#[derive(Deserialize)]
+struct NativeLogprob {
+    logprob: f64,
+    #[serde(default)]
+    decoded_token: Option<String>,
+}
+
+/// One position of the native route's logprobs: a map from token id to its logprob.
+/// vLLM puts the sampled token first and then the top-k alternatives, so the entries
+/// are kept in the order they arrive.
+struct NativePosition(Vec<(String, NativeLogprob)>);
+
+impl<'de> Deserialize<'de> for NativePosition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct PositionVisitor;
+
+        impl<'de> Visitor<'de> for PositionVisitor {
+            type Value = NativePosition;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of token ids to logprobs")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: MapAccess<'de> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry::<String, NativeLogprob>()? {
+                    entries.push(entry);
+                }
+                Ok(NativePosition(entries))
+            }
+        }
+
+        deserializer.deserialize_map(PositionVisitor)
+    }
+}
+
+impl NativePosition {
+    fn into_token_logprob(self) -> Option<TokenLogprob> {
+        let mut top_logprobs: Vec<TopLogprob> = self.0
+            .into_iter()
+            .map(|(id, entry)| TopLogprob { token: entry.decoded_token.unwrap_or(id), logprob: entry.logprob })
+            .collect();
+        let sampled = top_logprobs.first()?.clone();
+        top_logprobs.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+        Some(TokenLogprob {
+            token: sampled.token,
+            logprob: sampled.logprob,
+            top_logprobs,
+        })
+    }
+}
+
+// The OpenAI chat route returns a token list under `content`, the OpenAI completions
+// route uses parallel arrays and the native route a map of token ids per position.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LogprobsRepr {
+    Tokens(Vec<TokenLogprob>),
+    Native(Vec<NativePosition>),
+    Completions {
+        tokens: Vec<String>,
+        token_logprobs: Vec<Option<f64>>,
+        #[serde(default)]
+        top_logprobs: Option<Vec<Option<BTreeMap<String, f64>>>>,
+    },
+    Chat {
+        content: Option<Vec<TokenLogprob>>,
+    },
+}
+
+impl From<LogprobsRepr> for Vec<TokenLogprob> {
+    fn from(repr: LogprobsRepr) -> Self {
+        match repr {
+            LogprobsRepr::Tokens(tokens) => tokens,
+            LogprobsRepr::Native(positions) =>
+                positions
+                    .into_iter()
+                    .filter_map(|position| position.into_token_logprob())
+                    .collect(),
+            LogprobsRepr::Chat { content } => content.unwrap_or_default(),
+            LogprobsRepr::Completions { tokens, token_logprobs, top_logprobs } => {
+                let mut top_logprobs = top_logprobs.unwrap_or_default().into_iter();
+                tokens
+                    .into_iter()
+                    .zip(token_logprobs)
+                    .map(|(token, logprob)| {
+                        let mut top: Vec<TopLogprob> = top_logprobs
+                            .next()
+                            .flatten()
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|(token, logprob)| TopLogprob { token, logprob })
+                            .collect();
+                        top.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+                        TokenLogprob {
+                            token,
+                            logprob: logprob.unwrap_or(f64::NEG_INFINITY),
+                            top_logprobs: top,
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// Logprobs are optional extras, so a shape we don't recognise is dropped rather than
+// failing the whole completion. The untagged enum buffers its input in order, so the
+// native route's token id maps keep the order the worker sent them in, provided the
+// output wasn't read into a `Value` beforehand (see `output::CompletionOutput`).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Lenient<T> {
+    Known(T),
+    Unknown(IgnoredAny),
+}
+
+impl<T> Lenient<T> {
+    fn known(self) -> Option<T> {
+        match self {
+            Lenient::Known(value) => Some(value),
+            Lenient::Unknown(_) => None,
+        }
+    }
+}
+
+pub(crate) fn deserialize_logprobs<'de, D>(deserializer: D) -> Result<Option<Vec<TokenLogprob>>, D::Error>
+    where D: Deserializer<'de>
+{
+    let value = Option::<Lenient<LogprobsRepr>>::deserialize(deserializer)?;
+    Ok(value.and_then(Lenient::known).map(|repr| repr.into()))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PromptLogprobsRepr {
+    Tokens(Vec<Option<TokenLogprob>>),
+    Native(Vec<Option<NativePosition>>),
+}
+
+/// Prompt logprobs have no entry for the first token, hence the `None`s.
+pub(crate) fn deserialize_prompt_logprobs<'de, D>(
+    deserializer: D
+) -> Result<Option<Vec<Option<TokenLogprob>>>, D::Error>
+    where D: Deserializer<'de>
+{
+    let value = Option::<Lenient<PromptLogprobsRepr>>::deserialize(deserializer)?;
+    Ok(
+        value.and_then(Lenient::known).map(|repr| match repr {
+            PromptLogprobsRepr::Tokens(tokens) => tokens,
+            PromptLogprobsRepr::Native(positions) =>
+                positions
+                    .into_iter()
+                    .map(|position| position.and_then(|p| p.into_token_logprob()))
+                    .collect(),
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::json;
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::vllm::{ Completion, VLLMParamBuilderTrait, VLLMParams, VLLM },
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    #[test]
+    fn test_native_logprobs() {
+        let completion: Completion = serde_json::from_value(json!({
+            "tokens": ["yes"],
+            "logprobs": [{
+                "token": "yes",
+                "logprob": -0.1,
+                "top_logprobs": [{ "token": "yes", "logprob": -0.1 }, { "token": "no", "logprob": -2.4 }]
+            }]
+        })).unwrap();
+
+        let logprobs = completion.logprobs.as_ref().unwrap();
+        assert_eq!(logprobs[0].top_logprobs[1].token, "no");
+        assert_eq!(completion.sequence_logprob(), Some(-0.1));
+    }
+
+    #[test]
+    fn test_openai_completions_logprobs() {
+        let completion: Completion = serde_json::from_value(json!({
+            "tokens": ["a b"],
+            "logprobs": {
+                "tokens": ["a", " b"],
+                "token_logprobs": [-1.0, -3.0],
+                "top_logprobs": [{ "a": -1.0, "c": -1.5 }, null]
+            }
+        })).unwrap();
+
+        let logprobs = completion.logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.len(), 2);
+        assert_eq!(logprobs[0].top_logprobs[1].token, "c");
+        assert!(logprobs[1].top_logprobs.is_empty());
+        assert_eq!(completion.sequence_logprob(), Some(-4.0));
+        assert!((completion.perplexity().unwrap() - 2f64.exp()).abs() < 1e-9);
+    }
+
+    // Raw JSON rather than `json!`, whose maps would sort the token ids.
+    const NATIVE_TOKEN_IDS: &str = r#"{
+        "tokens": ["hi there"],
+        "logprobs": [
+            { "9906": { "logprob": -0.2, "rank": 1, "decoded_token": "hi" } },
+            {
+                "5743": { "logprob": -1.5, "rank": 2, "decoded_token": " there" },
+                "1234": { "logprob": -0.4, "rank": 1, "decoded_token": " all" }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_native_token_id_logprobs() {
+        let completion: Completion = serde_json::from_str(NATIVE_TOKEN_IDS).unwrap();
+
+        let logprobs = completion.logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.len(), 2);
+        assert_eq!((logprobs[0].token.as_str(), logprobs[0].logprob), ("hi", -0.2));
+        // The sampled token comes first, even when it isn't the most likely one.
+        assert_eq!((logprobs[1].token.as_str(), logprobs[1].logprob), (" there", -1.5));
+        assert_eq!(logprobs[1].top_logprobs[0].token, " all");
+        assert_eq!(completion.sequence_logprob(), Some(-1.7));
+    }
+
+    #[tokio::test]
+    async fn test_native_token_id_order_survives_the_job_queue() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/vllm-test/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        let body = std::format!(
+            r#"{{ "id": "job-1", "status": "COMPLETED", "output": [{{ "choices": [{}] }}] }}"#,
+            NATIVE_TOKEN_IDS
+        );
+        Mock::given(method("GET"))
+            .and(path("/vllm-test/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(VLLM)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("vllm-test".to_owned())
+            .build();
+        let completion = client.request(VLLMParams::new().with_prompt("hi".to_owned())).await.unwrap();
+
+        let logprobs = completion.output.as_ref().unwrap()[0].choices[0].logprobs.clone().unwrap();
+        assert_eq!(logprobs[1].token, " there");
+        assert_eq!(logprobs[1].logprob, -1.5);
+    }
+
+    #[test]
+    fn test_unknown_logprobs_shape_is_ignored() {
+        let completion: Completion = serde_json::from_value(json!({
+            "tokens": ["hi"],
+            "logprobs": [1, 2]
+        })).unwrap();
+        assert!(completion.logprobs.is_none());
+    }
+
+    #[test]
+    fn test_missing_logprobs() {
+        let completion: Completion = serde_json::from_value(json!({ "tokens": ["hi"] })).unwrap();
+        assert!(completion.logprobs.is_none());
+        assert_eq!(completion.perplexity(), None);
+    }
+}
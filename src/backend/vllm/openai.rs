@@ -0,0 +1,192 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ Map, Value };
+
+use crate::{
+    backend::backend::RunpodParams,
+    client::client::{ RunpodClient, RunpodClientAPI },
+};
+
+use super::{ request_on, VLLMCompletion, VLLMParams, VLLM };
+
+pub const OPENAI_CHAT_ROUTE: &str = "/v1/chat/completions";
+pub const OPENAI_COMPLETIONS_ROUTE: &str = "/v1/completions";
+
+/// A request for the worker's OpenAI compatible route. The `model` picks the served
+/// model, or a LoRA adapter by its registered name.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VLLMOpenAIParams {
+    openai_route: String,
+    openai_input: Value,
+}
+
+impl RunpodParams for VLLMOpenAIParams {}
+
+impl VLLMOpenAIParams {
+    pub fn route(&self) -> &str {
+        &self.openai_route
+    }
+
+    pub fn input(&self) -> &Value {
+        &self.openai_input
+    }
+}
+
+impl VLLMParams {
+    /// Converts native params for the OpenAI route: chat completions when messages are
+    /// set, plain completions otherwise. Sampling params are flattened into the body.
+    /// A LoRA adapter is served under its own name, so it replaces `model`. The chat
+    /// template is always applied to messages and never to a prompt, so asking for
+    /// anything else is an error.
+    pub fn into_openai(self, model: String) -> Result<VLLMOpenAIParams, Error> {
+        let chat = self.messages.is_some();
+        if let Some(apply_chat_template) = self.apply_chat_template {
+            if apply_chat_template != chat {
+                return Err(
+                    Error::msg(
+                        std::format!(
+                            "The OpenAI route {} the chat template {}",
+                            if chat { "always applies" } else { "can't apply" },
+                            if chat { "to messages" } else { "to a prompt" }
+                        )
+                    )
+                );
+            }
+        }
+
+        let mut input = Map::new();
+        input.insert("model".to_owned(), Value::String(self.lora_adapter.unwrap_or(model)));
+
+        match self.messages {
+            Some(messages) => {
+                input.insert("messages".to_owned(), serde_json::to_value(messages)?);
+            }
+            None => {
+                input.insert("prompt".to_owned(), Value::String(self.prompt));
+            }
+        }
+        if let Some(tools) = self.tools {
+            input.insert("tools".to_owned(), serde_json::to_value(tools)?);
+        }
+        if let Some(tool_choice) = self.tool_choice {
+            input.insert("tool_choice".to_owned(), serde_json::to_value(tool_choice)?);
+        }
+        if let Some(stream) = self.stream {
+            input.insert("stream".to_owned(), Value::Bool(stream));
+        }
+        if let Some(sampling_params) = self.sampling_params {
+            if let Value::Object(fields) = serde_json::to_value(sampling_params)? {
+                input.extend(fields);
+            }
+        }
+
+        // Chat completions take a flag plus a separate top-k count.
+        if chat {
+            if let Some(top_k) = input.remove("logprobs") {
+                input.insert("logprobs".to_owned(), Value::Bool(true));
+                input.insert("top_logprobs".to_owned(), top_k);
+            }
+        }
+
+        Ok(VLLMOpenAIParams {
+            openai_route: (if chat { OPENAI_CHAT_ROUTE } else { OPENAI_COMPLETIONS_ROUTE }).to_owned(),
+            openai_input: Value::Object(input),
+        })
+    }
+}
+
+#[async_trait]
+impl RunpodClientAPI<VLLMOpenAIParams, Result<VLLMCompletion, Error>> for RunpodClient<VLLM> {
+    async fn request(&self, params: VLLMOpenAIParams) -> Result<VLLMCompletion, Error> {
+        request_on(self, self.machine_id.clone(), params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::backend::vllm::{
+        content::ContentPart,
+        ChatMessage,
+        VLLMParamBuilderTrait,
+        VLLMParams,
+        VLLMSamplingParamBuilderTrait,
+        VLLMSamplingParams,
+    };
+
+    #[test]
+    fn test_chat_params_to_openai() {
+        let params = VLLMParams::new()
+            .with_messages(vec![ChatMessage::user("Hi".to_owned())])
+            .with_sampling_params(VLLMSamplingParams::new().with_temperature(0.2).with_logprobs(3))
+            .into_openai("support-bot".to_owned())
+            .unwrap();
+
+        assert_eq!(params.route(), "/v1/chat/completions");
+        assert_eq!(
+            serde_json::to_value(&params).unwrap(),
+            json!({
+                "openai_route": "/v1/chat/completions",
+                "openai_input": {
+                    "model": "support-bot",
+                    "messages": [{ "role": "user", "content": "Hi" }],
+                    "temperature": 0.2,
+                    "logprobs": true,
+                    "top_logprobs": 3
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_prompt_params_to_openai() {
+        let params = VLLMParams::new()
+            .with_prompt("Once upon".to_owned())
+            .into_openai("base".to_owned())
+            .unwrap();
+
+        assert_eq!(params.route(), "/v1/completions");
+        assert_eq!(params.input()["prompt"], "Once upon");
+    }
+
+    #[test]
+    fn test_adapter_and_chat_template_to_openai() {
+        let params = VLLMParams::new()
+            .with_prompt("Once upon".to_owned())
+            .with_lora_adapter("stories-v1".to_owned())
+            .into_openai("base".to_owned())
+            .unwrap();
+        assert_eq!(params.input()["model"], "stories-v1");
+
+        let prompt = VLLMParams::new().with_prompt("Once upon".to_owned());
+        assert!(prompt.clone().apply_chat_template(true).into_openai("base".to_owned()).is_err());
+        assert!(prompt.apply_chat_template(false).into_openai("base".to_owned()).is_ok());
+
+        let chat = VLLMParams::new().with_messages(vec![ChatMessage::user("Hi".to_owned())]);
+        assert!(chat.clone().apply_chat_template(false).into_openai("base".to_owned()).is_err());
+        assert!(chat.apply_chat_template(true).into_openai("base".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn test_vision_messages_on_both_routes() {
+        let message = ChatMessage::user_parts(
+            vec![
+                ContentPart::text("Describe this.".to_owned()),
+                ContentPart::image_url("https://example.com/cat.png").unwrap()
+            ]
+        );
+        let expected = json!([{
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "Describe this." },
+                { "type": "image_url", "image_url": { "url": "https://example.com/cat.png" } }
+            ]
+        }]);
+
+        let params = VLLMParams::new().with_messages(vec![message]);
+        assert_eq!(serde_json::to_value(&params).unwrap()["messages"], expected);
+        assert_eq!(params.into_openai("llava".to_owned()).unwrap().input()["messages"], expected);
+    }
+}
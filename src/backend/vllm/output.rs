@@ -0,0 +1,169 @@
+use std::fmt;
+
+use serde::{ de::{ value::MapAccessDeserializer, MapAccess, SeqAccess, Visitor }, Deserialize, Deserializer };
+
+use super::{ logprobs::deserialize_logprobs, Completion, CompletionChoice, TokenLogprob, ToolCall };
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TokensRepr {
+    List(Vec<String>),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MessageRepr {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// Every completion shape the worker has returned over time: the native `tokens` list,
+/// OpenAI completions `text` and OpenAI chat `message`.
+#[derive(Deserialize)]
+pub(crate) struct CompletionRepr {
+    #[serde(default)]
+    tokens: Option<TokensRepr>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    message: Option<MessageRepr>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default)]
+    finish_reason: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_logprobs")]
+    logprobs: Option<Vec<TokenLogprob>>,
+}
+
+impl From<CompletionRepr> for Completion {
+    fn from(repr: CompletionRepr) -> Self {
+        let (message_content, message_tool_calls) = match repr.message {
+            Some(message) => (message.content, message.tool_calls),
+            None => (None, None),
+        };
+        let tokens = match (repr.tokens, repr.text, message_content) {
+            (Some(TokensRepr::List(tokens)), _, _) => tokens,
+            (Some(TokensRepr::Text(text)), _, _) | (None, Some(text), _) | (None, None, Some(text)) =>
+                vec![text],
+            (None, None, None) => Vec::new(),
+        };
+
+        Completion {
+            tokens,
+            tool_calls: repr.tool_calls.or(message_tool_calls),
+            finish_reason: repr.finish_reason,
+            logprobs: repr.logprobs,
+        }
+    }
+}
+
+/// The worker's output: a list of batches, or a single batch from the OpenAI routes.
+///
+/// It is read straight from the response rather than through a `Value`, whose maps
+/// sort their keys: the native route's logprobs are maps of token ids in which the
+/// sampled token comes first, and that order has to survive.
+pub(crate) struct CompletionOutput(pub(crate) Vec<CompletionChoice>);
+
+impl<'de> Deserialize<'de> for CompletionOutput {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct OutputVisitor;
+
+        impl<'de> Visitor<'de> for OutputVisitor {
+            type Value = CompletionOutput;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a completion batch or a list of them")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: SeqAccess<'de> {
+                let mut batches = Vec::new();
+                while let Some(batch) = seq.next_element::<CompletionChoice>()? {
+                    batches.push(batch);
+                }
+                Ok(CompletionOutput(batches))
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error> where A: MapAccess<'de> {
+                let batch = CompletionChoice::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(CompletionOutput(vec![batch]))
+            }
+        }
+
+        deserializer.deserialize_any(OutputVisitor)
+    }
+}
+
+/// Accepts the output either as a list of batches or as a single object.
+pub(crate) fn deserialize_output<'de, D>(deserializer: D) -> Result<Option<Vec<CompletionChoice>>, D::Error>
+    where D: Deserializer<'de>
+{
+    Ok(Option::<CompletionOutput>::deserialize(deserializer)?.map(|output| output.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::backend::vllm::{ CompletionUsage, VLLMCompletion };
+
+    #[test]
+    fn test_native_output_batches() {
+        let completion: VLLMCompletion = serde_json::from_value(json!({
+            "id": "job-1",
+            "status": "COMPLETED",
+            "output": [
+                { "choices": [{ "tokens": ["Hello"] }, { "tokens": ["Hi"] }], "usage": { "input": 5, "output": 1 } },
+                { "choices": [{ "tokens": [" world"], "finish_reason": "stop" }, { "tokens": [" there"] }], "usage": { "input": 5, "output": 1 } }
+            ]
+        })).unwrap();
+
+        assert_eq!(completion.text().unwrap(), "Hello world");
+        assert_eq!(completion.texts().unwrap(), vec!["Hello world", "Hi there"]);
+        assert_eq!(completion.finish_reason().unwrap().as_deref(), Some("stop"));
+        assert_eq!(completion.usage().unwrap(), CompletionUsage { input: Some(5), output: Some(2) });
+    }
+
+    #[test]
+    fn test_openai_chat_output() {
+        let completion: VLLMCompletion = serde_json::from_value(json!({
+            "id": "job-2",
+            "status": "COMPLETED",
+            "output": {
+                "id": "cmpl-1",
+                "object": "chat.completion",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Sure." },
+                    "finish_reason": "length"
+                }],
+                "usage": { "prompt_tokens": 7, "completion_tokens": 2, "total_tokens": 9 }
+            }
+        })).unwrap();
+
+        assert_eq!(completion.text().unwrap(), "Sure.");
+        assert_eq!(completion.finish_reason().unwrap().as_deref(), Some("length"));
+        assert_eq!(completion.usage().unwrap(), CompletionUsage { input: Some(7), output: Some(2) });
+    }
+
+    #[test]
+    fn test_openai_completions_output() {
+        let completion: VLLMCompletion = serde_json::from_value(json!({
+            "status": "COMPLETED",
+            "output": [{ "choices": [{ "index": 0, "text": "42" }], "usage": { "prompt_tokens": 3, "completion_tokens": 1 } }]
+        })).unwrap();
+
+        assert_eq!(completion.text().unwrap(), "42");
+    }
+
+    #[test]
+    fn test_empty_output_is_an_error() {
+        let completion: VLLMCompletion = serde_json::from_value(json!({ "status": "COMPLETED", "output": [] })).unwrap();
+        assert!(completion.text().is_err());
+        assert!(completion.usage().is_err());
+
+        let completion: VLLMCompletion = serde_json::from_value(json!({ "status": "IN_PROGRESS" })).unwrap();
+        assert!(completion.finish_reason().is_err());
+    }
+}
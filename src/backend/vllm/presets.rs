@@ -0,0 +1,150 @@
+use std::{ collections::HashMap, fs, path::Path };
+
+use anyhow::Error;
+
+use super::{ VLLMSamplingParamBuilderTrait, VLLMSamplingParams };
+
+impl VLLMSamplingParams {
+    /// Greedy decoding, for reproducible output.
+    pub fn deterministic() -> Self {
+        VLLMSamplingParams::new().with_temperature(0.0)
+    }
+
+    pub fn creative() -> Self {
+        VLLMSamplingParams::new()
+            .with_temperature(1.0)
+            .with_top_p(0.95)
+            .with_presence_penalty(0.5)
+    }
+
+    pub fn beam(width: u64) -> Self {
+        VLLMSamplingParams::new()
+            .use_beam_search(true)
+            .with_best_of(width)
+            .with_temperature(0.0)
+    }
+}
+
+/// Named sampling configurations. Starts with the built-in `deterministic`, `creative`
+/// and `beam` presets; a TOML file can add to or override them, one table per preset:
+///
+/// ```toml
+/// [summarize]
+/// temperature = 0.3
+/// max_tokens = 256
+/// ```
+#[derive(Debug, Clone)]
+pub struct SamplingPresets {
+    presets: HashMap<String, VLLMSamplingParams>,
+}
+
+impl Default for SamplingPresets {
+    fn default() -> Self {
+        let mut presets = HashMap::new();
+        presets.insert("deterministic".to_owned(), VLLMSamplingParams::deterministic());
+        presets.insert("creative".to_owned(), VLLMSamplingParams::creative());
+        presets.insert("beam".to_owned(), VLLMSamplingParams::beam(4));
+        Self { presets }
+    }
+}
+
+impl SamplingPresets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads presets from TOML source, validating every entry.
+    pub fn from_toml_str(source: &str) -> Result<Self, Error> {
+        let loaded: HashMap<String, VLLMSamplingParams> = toml::from_str(source)?;
+        let mut presets = Self::default();
+        for (name, params) in loaded {
+            params
+                .validate()
+                .map_err(|e| Error::msg(std::format!("Preset '{}': {}", name, e)))?;
+            presets.presets.insert(name, params);
+        }
+        Ok(presets)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_toml_str(&fs::read_to_string(path)?)
+    }
+
+    pub fn insert(&mut self, name: String, params: VLLMSamplingParams) -> Result<(), Error> {
+        params.validate()?;
+        self.presets.insert(name, params);
+        Ok(())
+    }
+
+    /// A copy of the named preset, ready to be refined with further builder calls.
+    pub fn get(&self, name: &str) -> Result<VLLMSamplingParams, Error> {
+        self.presets
+            .get(name)
+            .cloned()
+            .ok_or(Error::msg(std::format!("Unknown sampling preset '{}'", name)))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.presets.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::vllm::{ VLLMSamplingParamBuilderTrait, VLLMSamplingParams };
+
+    use super::SamplingPresets;
+
+    #[test]
+    fn test_builtin_presets_are_valid() {
+        let presets = SamplingPresets::new();
+        for name in presets.names() {
+            presets.get(name).unwrap().validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_build_rejects_invalid_params() {
+        assert!(VLLMSamplingParams::new().with_top_p(5.0).build().is_err());
+        assert!(VLLMSamplingParams::new().with_n(4).with_best_of(2).build().is_err());
+        assert!(VLLMSamplingParams::beam(4).with_temperature(0.7).build().is_err());
+        assert!(VLLMSamplingParams::new().with_length_penalty(1.2).build().is_err());
+        assert!(VLLMSamplingParams::new().with_repetition_penalty(0.0).build().is_err());
+        assert!(VLLMSamplingParams::new().with_repetition_penalty(3.0).build().is_ok());
+        assert!(VLLMSamplingParams::new().with_n(2).with_best_of(4).with_top_p(0.9).build().is_ok());
+    }
+
+    #[test]
+    fn test_presets_from_toml() {
+        let presets = SamplingPresets::from_toml_str(
+            r#"
+            [summarize]
+            temperature = 0.3
+            max_tokens = 256
+
+            [deterministic]
+            temperature = 0.0
+            max_tokens = 64
+            "#
+        ).unwrap();
+
+        assert_eq!(
+            presets.get("summarize").unwrap(),
+            VLLMSamplingParams::new().with_temperature(0.3).with_max_tokens(256)
+        );
+        assert_eq!(
+            presets.get("deterministic").unwrap(),
+            VLLMSamplingParams::deterministic().with_max_tokens(64)
+        );
+        assert!(presets.get("creative").is_ok());
+        assert!(presets.get("missing").is_err());
+    }
+
+    #[test]
+    fn test_invalid_toml_preset_is_rejected() {
+        assert!(SamplingPresets::from_toml_str("[bad]\ntop_p = 5.0\n").is_err());
+        assert!(SamplingPresets::from_toml_str("[typo]\ntemprature = 0.5\n").is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use serde::{ Deserialize, Serialize };
+
+use crate::client::client::RunpodClient;
+
+use super::{ request_on, VLLMCompletion, VLLMParamBuilderTrait, VLLMParams, VLLM };
+
+/// Where a logical model is served: the endpoint and, optionally, the LoRA adapter
+/// loaded on top of its base model.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ModelRoute {
+    pub machine_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+}
+
+/// Maps logical model names to endpoints so one client can serve several fine-tunes.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ModelRegistry {
+    routes: HashMap<String, ModelRoute>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn register(mut self, name: String, machine_id: String, adapter: Option<String>) -> Self {
+        self.routes.insert(name, ModelRoute { machine_id, adapter });
+        self
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<&ModelRoute, Error> {
+        self.routes.get(name).ok_or(Error::msg(std::format!("Unknown model '{}'", name)))
+    }
+
+    /// Sends native `params` to the endpoint serving `name`, selecting its adapter.
+    pub async fn request(
+        &self,
+        client: &RunpodClient<VLLM>,
+        name: &str,
+        params: VLLMParams
+    ) -> Result<VLLMCompletion, Error> {
+        let route = self.resolve(name)?;
+        let params = match route.adapter.clone() {
+            Some(adapter) => params.with_lora_adapter(adapter),
+            None => params,
+        };
+        request_on(client, route.machine_id.clone(), params).await
+    }
+
+    /// Sends `params` through the OpenAI route of the endpoint serving `name`. The
+    /// adapter name is used as the model, falling back to the logical name.
+    pub async fn request_openai(
+        &self,
+        client: &RunpodClient<VLLM>,
+        name: &str,
+        params: VLLMParams
+    ) -> Result<VLLMCompletion, Error> {
+        let route = self.resolve(name)?;
+        let model = route.adapter.clone().unwrap_or(name.to_owned());
+        request_on(client, route.machine_id.clone(), params.into_openai(model)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::vllm::{ VLLMParamBuilderTrait, VLLMParams, VLLM },
+        client::client::{ RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::ModelRegistry;
+
+    #[tokio::test]
+    async fn test_registry_routes_to_endpoint_and_adapter() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/llama-loras/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/llama-loras/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "status": "COMPLETED",
+                "output": [{ "choices": [{ "tokens": ["ok"] }], "usage": { "input": 1, "output": 1 } }]
+            })))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(VLLM)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("default-endpoint".to_owned())
+            .build();
+        let registry = ModelRegistry::new()
+            .register("legal".to_owned(), "llama-loras".to_owned(), Some("legal-v2".to_owned()))
+            .register("medical".to_owned(), "llama-loras".to_owned(), Some("medical-v1".to_owned()));
+
+        let native = registry
+            .request(&client, "legal", VLLMParams::new().with_prompt("Hi".to_owned())).await
+            .unwrap();
+        assert_eq!(native.text().unwrap(), "ok");
+        registry
+            .request_openai(&client, "medical", VLLMParams::new().with_prompt("Hi".to_owned())).await
+            .unwrap();
+        assert!(registry.resolve("finance").is_err());
+
+        let runs: Vec<Value> = server
+            .received_requests().await
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.url.path().ends_with("/run"))
+            .map(|r| r.body_json().unwrap())
+            .collect();
+        assert_eq!(runs[0]["input"]["lora_adapter"], "legal-v2");
+        assert_eq!(runs[1]["input"]["openai_input"]["model"], "medical-v1");
+    }
+}
@@ -0,0 +1,266 @@
+use std::path::Path;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use log::{ info, warn };
+use tokenizers::Tokenizer;
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{ CompletionUsage, VLLMCompletion, VLLMParams, VLLM };
+
+/// vLLM generates this many tokens when `max_tokens` isn't set.
+pub const DEFAULT_MAX_TOKENS: u64 = 16;
+
+/// Tokens a chat template typically spends on role markers around each message.
+pub const DEFAULT_MESSAGE_OVERHEAD: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    Warn,
+    Fail,
+}
+
+/// Prompt size of a request, measured locally before it is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenEstimate {
+    pub prompt_tokens: usize,
+    pub max_tokens: u64,
+    pub context_length: Option<usize>,
+}
+
+impl TokenEstimate {
+    /// The most tokens the request can consume: the prompt plus the full completion budget.
+    pub fn max_total(&self) -> u64 {
+        (self.prompt_tokens as u64) + self.max_tokens
+    }
+
+    pub fn exceeds_context(&self) -> bool {
+        self.context_length.is_some_and(|context| self.max_total() > (context as u64))
+    }
+
+    /// Difference between the input tokens the worker reported and the local estimate,
+    /// positive when the estimate was too low.
+    pub fn compare(&self, usage: &CompletionUsage) -> Option<i64> {
+        usage.input.map(|input| (input as i64) - (self.prompt_tokens as i64))
+    }
+}
+
+/// Counts prompt tokens with a local copy of the endpoint model's `tokenizer.json`.
+pub struct PromptTokenizer {
+    tokenizer: Tokenizer,
+    context_length: Option<usize>,
+    policy: OverflowPolicy,
+    message_overhead: usize,
+}
+
+impl PromptTokenizer {
+    pub fn new(tokenizer: Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            context_length: None,
+            policy: OverflowPolicy::Warn,
+            message_overhead: DEFAULT_MESSAGE_OVERHEAD,
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Tokenizer::from_file(path).map(Self::new).map_err(Error::msg)
+    }
+
+    pub fn with_context_length(mut self, context_length: usize) -> Self {
+        self.context_length = Some(context_length);
+        self
+    }
+
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_message_overhead(mut self, message_overhead: usize) -> Self {
+        self.message_overhead = message_overhead;
+        self
+    }
+
+    pub fn count(&self, text: &str) -> Result<usize, Error> {
+        self.tokenizer
+            .encode(text, false)
+            .map(|encoding| encoding.len())
+            .map_err(Error::msg)
+    }
+
+    /// Tokens in the prompt, or in the messages plus their template overhead.
+    pub fn count_params(&self, params: &VLLMParams) -> Result<usize, Error> {
+        match params.messages.as_ref() {
+            Some(messages) => {
+                let mut total = 0;
+                for message in messages {
+                    total += self.message_overhead;
+                    if let Some(content) = message.text() {
+                        total += self.count(&content)?;
+                    }
+                }
+                Ok(total)
+            }
+            None => self.count(&params.prompt),
+        }
+    }
+
+    /// Estimates the request and applies the overflow policy if the prompt plus
+    /// `max_tokens` doesn't fit the configured context length.
+    pub fn check(&self, params: &VLLMParams) -> Result<TokenEstimate, Error> {
+        let estimate = TokenEstimate {
+            prompt_tokens: self.count_params(params)?,
+            max_tokens: params.sampling_params
+                .as_ref()
+                .and_then(|s| s.max_tokens)
+                .unwrap_or(DEFAULT_MAX_TOKENS),
+            context_length: self.context_length,
+        };
+
+        if estimate.exceeds_context() {
+            let msg = std::format!(
+                "Prompt of {} tokens plus max_tokens {} exceeds context length {}",
+                estimate.prompt_tokens,
+                estimate.max_tokens,
+                estimate.context_length.unwrap_or_default()
+            );
+            match self.policy {
+                OverflowPolicy::Warn => warn!("{}", msg),
+                OverflowPolicy::Fail => {
+                    return Err(Error::msg(msg));
+                }
+            }
+        }
+        Ok(estimate)
+    }
+}
+
+#[async_trait]
+pub trait VLLMTokenBudget {
+    /// Checks `params` against `tokenizer` before sending them, then logs how far the
+    /// estimate was from the input usage the worker reported.
+    async fn request_checked(
+        &self,
+        tokenizer: &PromptTokenizer,
+        params: VLLMParams
+    ) -> Result<(VLLMCompletion, TokenEstimate), Error>;
+}
+
+#[async_trait]
+impl VLLMTokenBudget for RunpodClient<VLLM> {
+    async fn request_checked(
+        &self,
+        tokenizer: &PromptTokenizer,
+        params: VLLMParams
+    ) -> Result<(VLLMCompletion, TokenEstimate), Error> {
+        let estimate = tokenizer.check(&params)?;
+        let completion = self.request(params).await?;
+
+        if let Some(drift) = completion.usage().ok().and_then(|usage| estimate.compare(&usage)) {
+            info!("VLLM Prompt estimate {} tokens, off by {}", estimate.prompt_tokens, drift);
+        }
+        Ok((completion, estimate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use reqwest::Url;
+    use serde_json::json;
+    use tokenizers::Tokenizer;
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::vllm::{
+            ChatMessage,
+            CompletionUsage,
+            VLLMParamBuilderTrait,
+            VLLMParams,
+            VLLMSamplingParamBuilderTrait,
+            VLLMSamplingParams,
+            VLLM,
+        },
+        client::client::{ RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ OverflowPolicy, PromptTokenizer, VLLMTokenBudget };
+
+    const TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "Whitespace" },
+        "post_processor": null,
+        "decoder": null,
+        "model": { "type": "WordLevel", "vocab": { "[UNK]": 0, "hello": 1, "world": 2 }, "unk_token": "[UNK]" }
+    }"#;
+
+    fn tokenizer() -> PromptTokenizer {
+        PromptTokenizer::new(Tokenizer::from_str(TOKENIZER).unwrap())
+    }
+
+    #[test]
+    fn test_count_prompt_and_messages() {
+        let tokenizer = tokenizer();
+        let prompt = VLLMParams::new().with_prompt("hello big world".to_owned());
+        assert_eq!(tokenizer.count_params(&prompt).unwrap(), 3);
+
+        let chat = VLLMParams::new().with_messages(vec![
+            ChatMessage::system("hello".to_owned()),
+            ChatMessage::user("hello world".to_owned())
+        ]);
+        assert_eq!(tokenizer.count_params(&chat).unwrap(), 3 + 2 * super::DEFAULT_MESSAGE_OVERHEAD);
+    }
+
+    #[test]
+    fn test_context_overflow_policy() {
+        let params = VLLMParams::new()
+            .with_prompt("hello world hello world".to_owned())
+            .with_sampling_params(VLLMSamplingParams::new().with_max_tokens(8));
+
+        let warn = tokenizer().with_context_length(10);
+        let estimate = warn.check(&params).unwrap();
+        assert_eq!(estimate.max_total(), 12);
+        assert!(estimate.exceeds_context());
+        assert_eq!(estimate.compare(&CompletionUsage { input: Some(6), output: None }), Some(2));
+
+        let fail = tokenizer().with_context_length(10).with_overflow_policy(OverflowPolicy::Fail);
+        assert!(fail.check(&params).is_err());
+        assert!(tokenizer().with_context_length(12).with_overflow_policy(OverflowPolicy::Fail).check(&params).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_checked_counts_prompt_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/vllm-test/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        // Streamed batches each repeat the prompt's input tokens.
+        Mock::given(method("GET"))
+            .and(path("/vllm-test/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-1",
+                "status": "COMPLETED",
+                "output": [
+                    { "choices": [{ "tokens": ["a"] }], "usage": { "input": 3, "output": 1 } },
+                    { "choices": [{ "tokens": [" b"] }], "usage": { "input": 3, "output": 1 } }
+                ]
+            })))
+            .mount(&server).await;
+        let client = RunpodClientBuilder::new(VLLM)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("vllm-test".to_owned())
+            .build();
+
+        let params = VLLMParams::new().with_prompt("hello big world".to_owned());
+        let (completion, estimate) = client.request_checked(&tokenizer(), params).await.unwrap();
+        assert_eq!(estimate.compare(&completion.usage().unwrap()), Some(0));
+    }
+}
@@ -0,0 +1,222 @@
+use std::{ collections::HashMap, future::Future, pin::Pin };
+
+use anyhow::Error;
+use async_trait::async_trait;
+use log::info;
+use serde_json::{ json, Value };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{ ChatMessage, Tool, ToolCall, VLLMCompletion, VLLMParams, VLLM };
+
+pub type ToolFuture = Pin<Box<dyn Future<Output = Result<Value, Error>> + Send>>;
+
+pub type ToolHandler = Box<dyn (Fn(Value) -> ToolFuture) + Send + Sync>;
+
+/// Maps tool names to their definitions and the async handlers that serve them.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+    handlers: HashMap<String, ToolHandler>,
+}
+
+impl ToolRegistry {
+    /// Registers `tool`, routing calls to it through `handler`. The handler receives the
+    /// parsed call arguments and returns the value reported back to the model.
+    pub fn register<F, Fut>(mut self, tool: Tool, handler: F) -> Self
+        where F: Fn(Value) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = Result<Value, Error>> + Send + 'static
+    {
+        let name = tool.function.name.clone();
+        self.tools.retain(|t| t.function.name != name);
+        self.tools.push(tool);
+        self.handlers.insert(
+            name,
+            Box::new(move |args| Box::pin(handler(args)))
+        );
+        self
+    }
+
+    pub fn tools(&self) -> Vec<Tool> {
+        self.tools.clone()
+    }
+
+    /// Runs a single tool call and wraps the outcome as a `tool` message. Handler
+    /// failures are reported to the model instead of aborting the conversation.
+    pub async fn dispatch(&self, call: &ToolCall) -> ChatMessage {
+        let result = match self.handlers.get(&call.function.name) {
+            Some(handler) => {
+                match call.function.parse_arguments::<Value>() {
+                    Ok(args) => handler(args).await,
+                    Err(e) => Err(e),
+                }
+            }
+            None => Err(Error::msg(std::format!("Unknown tool '{}'", call.function.name))),
+        };
+
+        let content = match result {
+            Ok(Value::String(s)) => s,
+            Ok(value) => value.to_string(),
+            Err(e) => json!({ "error": e.to_string() }).to_string(),
+        };
+
+        ChatMessage::tool(call.id.clone(), content)
+    }
+}
+
+#[async_trait]
+pub trait VLLMToolLoop {
+    /// Sends `params`, dispatching any requested tool calls to `registry` and feeding the
+    /// results back until the model answers without calling a tool. Fails after
+    /// `max_rounds` round trips.
+    async fn request_with_tools(
+        &self,
+        params: VLLMParams,
+        registry: &ToolRegistry,
+        max_rounds: usize
+    ) -> Result<VLLMCompletion, Error>;
+}
+
+#[async_trait]
+impl VLLMToolLoop for RunpodClient<VLLM> {
+    async fn request_with_tools(
+        &self,
+        mut params: VLLMParams,
+        registry: &ToolRegistry,
+        max_rounds: usize
+    ) -> Result<VLLMCompletion, Error> {
+        if params.tools.is_none() {
+            params.tools = Some(registry.tools());
+        }
+        let mut messages = params.messages.take().unwrap_or_default();
+
+        for _ in 0..max_rounds {
+            let mut round = params.clone();
+            round.messages = Some(messages.clone());
+            let completion = self.request(round).await?;
+
+            let choice = completion.output
+                .as_ref()
+                .and_then(|output| output.first())
+                .and_then(|choice| choice.choices.first());
+
+            let tool_calls = match choice.and_then(|c| c.tool_calls.clone()) {
+                Some(calls) if !calls.is_empty() => calls,
+                _ => {
+                    return Ok(completion);
+                }
+            };
+
+            let content = choice
+                .map(|c| c.tokens.concat())
+                .filter(|text| !text.is_empty());
+            messages.push(ChatMessage::assistant_tool_calls(content, tool_calls.clone()));
+
+            for call in tool_calls.iter() {
+                info!("VLLM Tool call: {} {}", call.function.name, call.function.arguments);
+                messages.push(registry.dispatch(call).await);
+            }
+        }
+
+        Err(Error::msg(std::format!("Tool loop did not finish within {} rounds.", max_rounds)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::vllm::{ ChatMessage, Tool, VLLMParamBuilderTrait, VLLMParams, VLLM },
+        client::client::{ RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ ToolRegistry, VLLMToolLoop };
+
+    #[tokio::test]
+    async fn test_tool_loop_dispatches_and_feeds_back() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/vllm-test/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .up_to_n_times(1)
+            .mount(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/vllm-test/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-2", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/vllm-test/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-1",
+                "status": "COMPLETED",
+                "output": [{
+                    "choices": [{
+                        "tokens": [""],
+                        "tool_calls": [{
+                            "id": "call-1",
+                            "type": "function",
+                            "function": { "name": "get_weather", "arguments": "{\"city\": \"Oslo\"}" }
+                        }]
+                    }],
+                    "usage": { "input": 12, "output": 8 }
+                }]
+            })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/vllm-test/status/job-2/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "job-2",
+                "status": "COMPLETED",
+                "output": [{
+                    "choices": [{ "tokens": ["It is 4 degrees in Oslo."] }],
+                    "usage": { "input": 30, "output": 9 }
+                }]
+            })))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(VLLM)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .with_machine_id("vllm-test".to_owned())
+            .build();
+
+        let registry = ToolRegistry::default().register(
+            Tool::function(
+                "get_weather".to_owned(),
+                "Current temperature for a city".to_owned(),
+                json!({ "type": "object", "properties": { "city": { "type": "string" } } })
+            ),
+            |args: Value| async move {
+                Ok(json!({ "city": args["city"], "celsius": 4 }))
+            }
+        );
+
+        let response = client.request_with_tools(
+            VLLMParams::new().with_messages(vec![ChatMessage::user("Weather in Oslo?".to_owned())]),
+            &registry,
+            4
+        ).await.unwrap();
+
+        assert_eq!(response.output.unwrap()[0].choices[0].tokens[0], "It is 4 degrees in Oslo.");
+
+        let runs: Vec<Value> = server
+            .received_requests().await
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.url.path().ends_with("/run"))
+            .map(|r| r.body_json().unwrap())
+            .collect();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0]["input"]["tools"][0]["function"]["name"], "get_weather");
+
+        let messages = runs[1]["input"]["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["tool_calls"][0]["id"], "call-1");
+        assert_eq!(messages[2]["role"], "tool");
+        assert_eq!(messages[2]["tool_call_id"], "call-1");
+        assert_eq!(messages[2]["content"], "{\"celsius\":4,\"city\":\"Oslo\"}");
+    }
+}
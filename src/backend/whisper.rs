@@ -0,0 +1,311 @@
+use std::{ fmt, str::FromStr };
+
+use anyhow::Error;
+use async_trait::async_trait;
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
+
+use crate::client::client::{ RunpodClient, RunpodClientAPI };
+
+use super::{ audio::AudioInput, backend::{ RunpodBackend, RunpodParams }, job::{ self, JobResult } };
+
+pub mod transcript;
+
+/// Path of the public Faster-Whisper endpoint under the API base.
+pub const WHISPER_ENDPOINT: &str = "faster-whisper";
+
+pub struct Whisper;
+
+impl RunpodBackend for Whisper {}
+
+/// Whisper models the worker has weights for. `Custom` passes any other name through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WhisperModel {
+    Tiny,
+    Base,
+    Small,
+    Medium,
+    LargeV1,
+    LargeV2,
+    LargeV3,
+    DistilLargeV2,
+    DistilLargeV3,
+    Turbo,
+    Custom(String),
+}
+
+impl WhisperModel {
+    pub fn as_str(&self) -> &str {
+        match self {
+            WhisperModel::Tiny => "tiny",
+            WhisperModel::Base => "base",
+            WhisperModel::Small => "small",
+            WhisperModel::Medium => "medium",
+            WhisperModel::LargeV1 => "large-v1",
+            WhisperModel::LargeV2 => "large-v2",
+            WhisperModel::LargeV3 => "large-v3",
+            WhisperModel::DistilLargeV2 => "distil-large-v2",
+            WhisperModel::DistilLargeV3 => "distil-large-v3",
+            WhisperModel::Turbo => "turbo",
+            WhisperModel::Custom(name) => name.as_str(),
+        }
+    }
+}
+
+impl fmt::Display for WhisperModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WhisperModel {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "tiny" => WhisperModel::Tiny,
+            "base" => WhisperModel::Base,
+            "small" => WhisperModel::Small,
+            "medium" => WhisperModel::Medium,
+            "large-v1" => WhisperModel::LargeV1,
+            "large-v2" => WhisperModel::LargeV2,
+            "large-v3" => WhisperModel::LargeV3,
+            "distil-large-v2" => WhisperModel::DistilLargeV2,
+            "distil-large-v3" => WhisperModel::DistilLargeV3,
+            "turbo" => WhisperModel::Turbo,
+            other => WhisperModel::Custom(other.to_owned()),
+        })
+    }
+}
+
+impl Serialize for WhisperModel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for WhisperModel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(name.parse().unwrap_or_else(|never| match never {}))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WhisperWord {
+    pub start: f64,
+    pub end: f64,
+    pub word: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WhisperSegment {
+    #[serde(default)]
+    pub id: u64,
+    #[serde(default)]
+    pub seek: u64,
+    /// Seconds from the start of the audio.
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    #[serde(default)]
+    pub tokens: Vec<i64>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub avg_logprob: Option<f64>,
+    #[serde(default)]
+    pub compression_ratio: Option<f64>,
+    #[serde(default)]
+    pub no_speech_prob: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WhisperOutput {
+    #[serde(default)]
+    pub segments: Vec<WhisperSegment>,
+    #[serde(default)]
+    pub detected_language: Option<String>,
+    /// The full transcript, in the format requested.
+    #[serde(default)]
+    pub transcription: Option<String>,
+    #[serde(default)]
+    pub translation: Option<String>,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Every word of the transcript in order. Only present when word timestamps were
+    /// requested.
+    #[serde(default)]
+    pub word_timestamps: Vec<WhisperWord>,
+}
+
+impl WhisperOutput {
+    /// The transcript, or the segment texts joined when the worker didn't return one.
+    pub fn text(&self) -> String {
+        match self.transcription.as_ref() {
+            Some(transcription) => transcription.clone(),
+            None =>
+                self.segments
+                    .iter()
+                    .map(|s| s.text.trim())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+        }
+    }
+
+    pub fn words(&self) -> impl Iterator<Item = &WhisperWord> {
+        self.word_timestamps.iter()
+    }
+
+    /// End of the last segment, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.segments
+            .last()
+            .map(|s| s.end)
+            .unwrap_or_default()
+    }
+}
+
+pub type WhisperResult = JobResult<WhisperOutput>;
+
+#[derive(Debug, Deserialize, Clone, Default, RunpodParams)]
+#[runpod(builder = WhisperParamBuilderTrait)]
+pub struct WhisperParams {
+    /// URL of the audio, for the worker to download.
+    #[runpod(skip)]
+    audio: Option<String>,
+    #[runpod(skip)]
+    audio_base64: Option<String>,
+    model: Option<WhisperModel>,
+    /// Spoken language as an ISO code; detected when unset.
+    language: Option<String>,
+    /// Also translate the transcript to English.
+    translate: Option<bool>,
+    word_timestamps: Option<bool>,
+    /// Skip silence with voice activity detection before transcribing.
+    #[runpod(setter = with_vad)]
+    enable_vad: Option<bool>,
+    temperature: Option<f64>,
+    beam_size: Option<u64>,
+    initial_prompt: Option<String>,
+}
+
+impl WhisperParams {
+    /// Sets the audio to transcribe, replacing any set before.
+    pub fn with_audio(mut self, audio: AudioInput) -> Self {
+        match audio {
+            AudioInput::Url(url) => {
+                self.audio = Some(url);
+                self.audio_base64 = None;
+            }
+            AudioInput::Base64(data) => {
+                self.audio_base64 = Some(data);
+                self.audio = None;
+            }
+        }
+        self
+    }
+}
+
+#[async_trait]
+impl RunpodClientAPI<WhisperParams, Result<WhisperResult, Error>> for RunpodClient<Whisper> {
+    async fn request(&self, params: WhisperParams) -> Result<WhisperResult, Error> {
+        if params.audio.is_none() && params.audio_base64.is_none() {
+            return Err(Error::msg("Whisper request has no audio"));
+        }
+        let queued = job::submit(&self.api_base, WHISPER_ENDPOINT, &self.api_key, &params).await?;
+        job::wait(&self.api_base, WHISPER_ENDPOINT, &self.api_key, &queued.id, self.poll_time).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use serde_json::{ json, Value };
+    use wiremock::{ matchers::{ method, path }, Mock, MockServer, ResponseTemplate };
+
+    use crate::{
+        backend::audio::AudioInput,
+        client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
+    };
+
+    use super::{ Whisper, WhisperModel, WhisperParamBuilderTrait, WhisperParams, WhisperResult };
+
+    const TRANSCRIPTION: &str = include_str!("../../tests/fixtures/whisper/transcription.json");
+    const TRANSLATION: &str = include_str!("../../tests/fixtures/whisper/translation.json");
+
+    #[test]
+    fn test_parse_recorded_responses() {
+        let transcription: WhisperResult = serde_json::from_str(TRANSCRIPTION).unwrap();
+        let output = transcription.output.unwrap();
+        assert_eq!(output.detected_language.as_deref(), Some("en"));
+        assert_eq!(output.segments.len(), 2);
+        assert_eq!(output.segments[1].start, 4.32);
+        assert_eq!(output.words().count(), 9);
+        assert_eq!(output.words().next().unwrap().word, " The");
+        assert_eq!(output.duration(), 7.8);
+
+        // Older worker versions leave out most segment fields and the transcript.
+        let translation: WhisperResult = serde_json::from_str(TRANSLATION).unwrap();
+        let output = translation.output.unwrap();
+        assert_eq!(output.translation.as_deref(), Some("Good morning, everyone."));
+        assert_eq!(output.text(), "Bonjour à tous.");
+        assert_eq!(output.words().count(), 0);
+    }
+
+    #[test]
+    fn test_audio_sources() {
+        let url = WhisperParams::new()
+            .with_audio(AudioInput::from_url("https://example.com/talk.mp3").unwrap())
+            .with_model(WhisperModel::LargeV3)
+            .with_vad(true)
+            .build();
+        assert_eq!(
+            serde_json::to_value(url).unwrap(),
+            json!({ "audio": "https://example.com/talk.mp3", "model": "large-v3", "enable_vad": true })
+        );
+
+        let file = std::env::temp_dir().join(std::format!("rpc-whisper-{}.wav", std::process::id()));
+        std::fs::write(&file, b"RIFF").unwrap();
+        let inline = WhisperParams::new().with_audio(AudioInput::from_path(&file).unwrap());
+        std::fs::remove_file(file).unwrap();
+        assert_eq!(serde_json::to_value(inline).unwrap(), json!({ "audio_base64": "UklGRg==" }));
+        assert!(AudioInput::from_url("ftp://example.com/talk.mp3").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_against_recorded_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/faster-whisper/run"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "job-1", "status": "IN_QUEUE" })))
+            .mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/faster-whisper/status/job-1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(TRANSCRIPTION))
+            .mount(&server).await;
+
+        let client = RunpodClientBuilder::new(Whisper)
+            .with_api_base(Url::parse(&std::format!("{}/", server.uri())).unwrap())
+            .build();
+        assert!(client.request(WhisperParams::new()).await.is_err());
+
+        let response = client
+            .request(
+                WhisperParams::new()
+                    .with_audio(AudioInput::from_url("https://example.com/talk.mp3").unwrap())
+                    .with_word_timestamps(true)
+                    .with_language("en".to_owned())
+            ).await
+            .unwrap();
+        assert_eq!(response.output.unwrap().segments[0].text, " The quick brown fox");
+
+        let sent = server.received_requests().await.unwrap();
+        let body = sent[0].body_json::<Value>().unwrap();
+        assert_eq!(body["input"]["word_timestamps"], true);
+        assert_eq!(body["input"]["language"], "en");
+    }
+}
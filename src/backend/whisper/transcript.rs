@@ -0,0 +1,330 @@
+use anyhow::Error;
+use serde::{ Deserialize, Serialize };
+
+use super::{ WhisperOutput, WhisperSegment, WhisperWord };
+
+/// How segments are broken into subtitle cues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueOptions {
+    /// Longest line before the text wraps.
+    pub max_line_chars: usize,
+    /// Lines per cue; text past this starts a new cue.
+    pub max_lines: usize,
+    /// Longest a cue stays on screen, in seconds.
+    pub max_duration: Option<f64>,
+}
+
+impl Default for CueOptions {
+    fn default() -> Self {
+        Self {
+            max_line_chars: 42,
+            max_lines: 2,
+            max_duration: Some(7.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscriptFormat {
+    Srt,
+    Vtt,
+    Txt,
+    Tsv,
+    Json,
+}
+
+/// One subtitle: its timing in seconds and its text, already wrapped into lines.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Cue {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<WhisperWord>,
+}
+
+/// Breaks segments into cues that fit `options`. Cues never span two segments. `words`
+/// are the transcript's word timings, which the worker reports for the whole transcript
+/// rather than per segment. Segments without any are split on whitespace with time
+/// shared out by length.
+pub fn cues(segments: &[WhisperSegment], words: &[WhisperWord], options: &CueOptions) -> Vec<Cue> {
+    let max_chars = options.max_line_chars * options.max_lines.max(1);
+    let mut cues = Vec::new();
+    for (segment, words) in segments.iter().zip(segment_words(segments, words)) {
+        let words = if words.is_empty() { interpolate_words(segment) } else { words.to_vec() };
+
+        let mut current: Vec<WhisperWord> = Vec::new();
+        for word in words {
+            if let Some(first) = current.first() {
+                let text = join_words(&current) + &word.word;
+                let too_long = text.trim().chars().count() > max_chars;
+                let too_slow = options.max_duration.is_some_and(|max| word.end - first.start > max);
+                if too_long || too_slow {
+                    cues.push(make_cue(std::mem::take(&mut current), options.max_line_chars));
+                }
+            }
+            current.push(word);
+        }
+        if !current.is_empty() {
+            cues.push(make_cue(current, options.max_line_chars));
+        }
+    }
+    cues
+}
+
+/// Splits the transcript's words between the segments, in order. A word belongs to the
+/// segment its midpoint falls in.
+fn segment_words<'a>(segments: &[WhisperSegment], words: &'a [WhisperWord]) -> Vec<&'a [WhisperWord]> {
+    let mut rest = words;
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            let count = if i + 1 == segments.len() {
+                rest.len()
+            } else {
+                rest.iter()
+                    .take_while(|w| (w.start + w.end) / 2.0 <= segment.end)
+                    .count()
+            };
+            let (words, remaining) = rest.split_at(count);
+            rest = remaining;
+            words
+        })
+        .collect()
+}
+
+fn interpolate_words(segment: &WhisperSegment) -> Vec<WhisperWord> {
+    let parts: Vec<&str> = segment.text.split_whitespace().collect();
+    let total: usize = parts
+        .iter()
+        .map(|p| p.chars().count())
+        .sum();
+    let span = segment.end - segment.start;
+    let mut elapsed = 0;
+    parts
+        .iter()
+        .map(|part| {
+            let start = segment.start + span * (elapsed as f64) / (total.max(1) as f64);
+            elapsed += part.chars().count();
+            let end = segment.start + span * (elapsed as f64) / (total.max(1) as f64);
+            WhisperWord { start, end, word: std::format!(" {}", part), probability: None }
+        })
+        .collect()
+}
+
+fn join_words(words: &[WhisperWord]) -> String {
+    words
+        .iter()
+        .map(|w| w.word.as_str())
+        .collect()
+}
+
+fn make_cue(words: Vec<WhisperWord>, max_line_chars: usize) -> Cue {
+    Cue {
+        start: words.first().map(|w| w.start).unwrap_or_default(),
+        end: words.last().map(|w| w.end).unwrap_or_default(),
+        text: wrap(join_words(&words).trim(), max_line_chars),
+        words,
+    }
+}
+
+/// Greedily wraps `text` at whitespace so no line is longer than `width`, unless a
+/// single word is.
+pub fn wrap(text: &str, width: usize) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_owned()),
+        }
+    }
+    lines.join("\n")
+}
+
+fn timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    std::format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+pub fn to_srt(cues: &[Cue]) -> String {
+    cues.iter()
+        .enumerate()
+        .map(|(i, cue)| {
+            std::format!("{}\n{} --> {}\n{}\n", i + 1, timestamp(cue.start, ','), timestamp(cue.end, ','), cue.text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn to_vtt(cues: &[Cue]) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for cue in cues {
+        vtt.push_str(&std::format!("\n{} --> {}\n{}\n", timestamp(cue.start, '.'), timestamp(cue.end, '.'), cue.text));
+    }
+    vtt
+}
+
+/// One line per segment.
+pub fn to_txt(segments: &[WhisperSegment]) -> String {
+    segments
+        .iter()
+        .map(|s| std::format!("{}\n", s.text.trim()))
+        .collect()
+}
+
+/// `start`, `end` and `text` columns, with times in whole milliseconds.
+pub fn to_tsv(segments: &[WhisperSegment]) -> String {
+    let mut tsv = String::from("start\tend\ttext\n");
+    for segment in segments {
+        tsv.push_str(
+            &std::format!(
+                "{}\t{}\t{}\n",
+                (segment.start * 1000.0).round() as u64,
+                (segment.end * 1000.0).round() as u64,
+                segment.text.trim().replace('\t', " ")
+            )
+        );
+    }
+    tsv
+}
+
+/// Cues with their word timings.
+pub fn to_json(cues: &[Cue]) -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(cues)? + "\n")
+}
+
+pub fn format(
+    segments: &[WhisperSegment],
+    words: &[WhisperWord],
+    format: TranscriptFormat,
+    options: &CueOptions
+) -> Result<String, Error> {
+    Ok(match format {
+        TranscriptFormat::Srt => to_srt(&cues(segments, words, options)),
+        TranscriptFormat::Vtt => to_vtt(&cues(segments, words, options)),
+        TranscriptFormat::Txt => to_txt(segments),
+        TranscriptFormat::Tsv => to_tsv(segments),
+        TranscriptFormat::Json => to_json(&cues(segments, words, options))?,
+    })
+}
+
+impl WhisperOutput {
+    pub fn format(&self, format: TranscriptFormat, options: &CueOptions) -> Result<String, Error> {
+        self::format(&self.segments, &self.word_timestamps, format, options)
+    }
+}
+
+fn parse_timestamp(stamp: &str) -> Result<f64, Error> {
+    let invalid = || Error::msg(std::format!("Invalid timestamp '{}'", stamp));
+    let (clock, millis) = stamp.trim().split_once([',', '.']).ok_or_else(invalid)?;
+    let mut seconds = 0.0;
+    for part in clock.split(':') {
+        seconds = seconds * 60.0 + part.parse::<u64>().map_err(|_| invalid())? as f64;
+    }
+    Ok(seconds + (millis.parse::<u64>().map_err(|_| invalid())? as f64) / 1000.0)
+}
+
+/// Reads the blocks of an SRT or VTT file that have a timing line.
+fn parse_blocks(text: &str) -> Result<Vec<Cue>, Error> {
+    let text = text.replace("\r\n", "\n");
+    let mut cues = Vec::new();
+    for block in text.split("\n\n") {
+        let lines: Vec<&str> = block.lines().collect();
+        let timing = match lines.iter().position(|l| l.contains("-->")) {
+            Some(timing) => timing,
+            None => {
+                continue;
+            }
+        };
+        let (start, rest) = lines[timing].split_once("-->").unwrap_or_default();
+        // VTT cue settings may follow the end time.
+        let end = rest.split_whitespace().next().unwrap_or_default();
+        cues.push(Cue {
+            start: parse_timestamp(start)?,
+            end: parse_timestamp(end)?,
+            text: lines[timing + 1..].join("\n"),
+            words: Vec::new(),
+        });
+    }
+    Ok(cues)
+}
+
+pub fn parse_srt(srt: &str) -> Result<Vec<Cue>, Error> {
+    parse_blocks(srt)
+}
+
+pub fn parse_vtt(vtt: &str) -> Result<Vec<Cue>, Error> {
+    if !vtt.trim_start_matches('\u{feff}').starts_with("WEBVTT") {
+        return Err(Error::msg("Missing WEBVTT header"));
+    }
+    // NOTE and STYLE blocks have no timing line, so they're skipped.
+    parse_blocks(vtt)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::whisper::WhisperResult;
+
+    use super::{ cues, parse_srt, parse_vtt, to_srt, to_vtt, wrap, CueOptions, TranscriptFormat };
+
+    const TRANSCRIPTION: &str = include_str!("../../../tests/fixtures/whisper/transcription.json");
+
+    fn options() -> CueOptions {
+        CueOptions { max_line_chars: 12, max_lines: 2, max_duration: Some(3.0) }
+    }
+
+    fn golden(format: TranscriptFormat, expected: &str) {
+        let result: WhisperResult = serde_json::from_str(TRANSCRIPTION).unwrap();
+        assert_eq!(result.output.unwrap().format(format, &options()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_golden_files() {
+        golden(TranscriptFormat::Srt, include_str!("../../../tests/fixtures/whisper/transcription.srt"));
+        golden(TranscriptFormat::Vtt, include_str!("../../../tests/fixtures/whisper/transcription.vtt"));
+        golden(TranscriptFormat::Txt, include_str!("../../../tests/fixtures/whisper/transcription.txt"));
+        golden(TranscriptFormat::Tsv, include_str!("../../../tests/fixtures/whisper/transcription.tsv"));
+        golden(TranscriptFormat::Json, include_str!("../../../tests/fixtures/whisper/cues.json"));
+    }
+
+    #[test]
+    fn test_split_and_wrap() {
+        assert_eq!(wrap("the quick brown fox", 10), "the quick\nbrown fox");
+        assert_eq!(wrap("supercalifragilistic is long", 10), "supercalifragilistic\nis long");
+
+        // Without word timings the segment is split with interpolated times.
+        let result: WhisperResult = serde_json::from_str(TRANSCRIPTION).unwrap();
+        let segments = result.output.unwrap().segments;
+        let split = cues(&segments[1..], &[], &CueOptions { max_line_chars: 10, max_lines: 1, max_duration: None });
+        assert_eq!(split.iter().map(|c| c.text.as_str()).collect::<Vec<_>>(), vec!["jumps over", "the lazy", "dog."]);
+        assert_eq!(split[0].start, 4.32);
+        assert_eq!(split[2].end, 7.8);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let srt = include_str!("../../../tests/fixtures/whisper/transcription.srt");
+        assert_eq!(to_srt(&parse_srt(srt).unwrap()), srt);
+
+        let vtt = include_str!("../../../tests/fixtures/whisper/transcription.vtt");
+        assert_eq!(to_vtt(&parse_vtt(vtt).unwrap()), vtt);
+
+        let styled = "WEBVTT\n\nNOTE made by hand\n\nintro\n01:02.500 --> 01:04.000 align:start\nHello\nthere\n";
+        let parsed = parse_vtt(styled).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!((parsed[0].start, parsed[0].end), (62.5, 64.0));
+        assert_eq!(parsed[0].text, "Hello\nthere");
+        assert!(parse_vtt("1\n00:00:00,000 --> 00:00:01,000\nHi\n").is_err());
+    }
+}
@@ -1 +1,2 @@
+#[allow(clippy::module_inception)] // `client::client` is part of the public path
 pub mod client;
\ No newline at end of file
@@ -54,11 +54,12 @@ async fn main() -> Result<(), anyhow::Error> {
                 .with_sampling_params(VLLMSamplingParams::new()
                     .with_max_tokens(512)
                     .with_temperature(1.0)
-                    .with_top_p(0.95))
+                    .with_top_p(0.95)
+                    .build()?)
                 .build()
         ).await?;
 
-        let completion = &resp.output.unwrap()[0].choices[0].tokens[0];
+        let completion = resp.text()?;
         println!("\n\nAssistant: {}", completion);
     }
 }
@@ -1,11 +1,14 @@
-use std::{ fs::File, io::{ stdin, stdout, Write } };
+use std::{ io::{ stdin, stdout, Write }, path::Path };
 
 use rpc::{
-    backend::sdxl::{
-        StableDiffusionXL,
-        StableDiffusionXLOutputFetch,
-        StableDiffusionXLParamBuilderTrait,
-        StableDiffusionXLParams,
+    backend::{
+        download::DownloadOptions,
+        sdxl::{
+            StableDiffusionXL,
+            StableDiffusionXLOutputFetch,
+            StableDiffusionXLParamBuilderTrait,
+            StableDiffusionXLParams,
+        },
     },
     client::client::{ RunpodClientAPI, RunpodClientBuilder, RunpodClientBuilderTrait },
 };
@@ -36,10 +39,15 @@ async fn main() -> Result<(), anyhow::Error> {
         let resp = client.request(
             StableDiffusionXLParams::new().with_prompt(s.clone()).build()
         ).await?;
-        let image_bytes = resp.output.clone().unwrap().fetch().await.unwrap();
         s.truncate(7);
-        let mut f = File::create(std::format!("./{}.png", s))?;
-        f.write_all(image_bytes.as_slice())?;
+        let path = std::format!("./{}", s);
+        let downloads = resp.output
+            .clone()
+            .unwrap()
+            .download_to(Path::new(&path), &DownloadOptions::new()).await?;
+        for download in downloads.iter() {
+            println!("Saved {}", download.path.display());
+        }
         println!("\n\nResult: {:#?}", resp);
     }
 }
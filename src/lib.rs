@@ -1,7 +1,11 @@
+// Lets `#[derive(RunpodParams)]` refer to this crate as `::rpc` from inside it too.
+extern crate self as rpc;
+
 pub mod client;
 pub mod backend;
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)] // the live-API tests predate the lint; leave them as written
 mod tests {
     use std::env;
 